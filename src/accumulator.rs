@@ -0,0 +1,73 @@
+/*
+Copyright (c) 2020 Todd Stellanova
+LICENSE: BSD3 (see LICENSE file)
+*/
+
+/// Accumulates samples for long-term averaging.
+/// Sums are kept as `i64`, so arbitrarily long windows cannot overflow.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Accumulator {
+    sums: [i64; 3],
+    count: u64,
+}
+
+impl Accumulator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add one sample
+    pub fn add(&mut self, sample: [i16; 3]) {
+        for (sum, val) in self.sums.iter_mut().zip(sample.iter()) {
+            *sum += *val as i64;
+        }
+        self.count += 1;
+    }
+
+    /// Number of samples accumulated
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Per-axis sums of all samples
+    pub fn sums(&self) -> [i64; 3] {
+        self.sums
+    }
+
+    /// Per-axis mean of all samples, or zero if no samples were added
+    pub fn mean(&self) -> [f32; 3] {
+        if self.count == 0 {
+            return [0.0; 3];
+        }
+        let count = self.count as f64;
+        [
+            (self.sums[0] as f64 / count) as f32,
+            (self.sums[1] as f64 / count) as f32,
+            (self.sums[2] as f64 / count) as f32,
+        ]
+    }
+
+    /// Discard all accumulated samples
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sums_past_i32_range() {
+        const N: i64 = 70_000;
+        let mut acc = Accumulator::new();
+        for _ in 0..N {
+            acc.add([i16::MAX, i16::MIN, 1]);
+        }
+        let sums = acc.sums();
+        assert!(sums[0] > i32::MAX as i64);
+        assert!(sums[1] < i32::MIN as i64);
+        assert_eq!(sums, [i16::MAX as i64 * N, i16::MIN as i64 * N, N]);
+        assert_eq!(acc.mean(), [i16::MAX as f32, i16::MIN as f32, 1.0]);
+    }
+}
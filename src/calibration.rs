@@ -0,0 +1,145 @@
+/*
+Copyright (c) 2020 Todd Stellanova
+LICENSE: BSD3 (see LICENSE file)
+*/
+
+/// 3x3 identity matrix
+pub const IDENTITY_MATRIX: [[f32; 3]; 3] =
+    [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+
+/// Software calibration applied to scaled (microtesla) readings:
+/// `soft_iron * ((reading - hard_iron) * axis_scale)`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Calibration {
+    /// Hard-iron offset per axis, in microtesla
+    pub hard_iron: [f32; 3],
+    /// Soft-iron correction matrix
+    pub soft_iron: [[f32; 3]; 3],
+    /// Scale factor per axis
+    pub axis_scale: [f32; 3],
+}
+
+impl Calibration {
+    /// Calibration that passes readings through unchanged
+    pub const IDENTITY: Calibration = Calibration {
+        hard_iron: [0.0; 3],
+        soft_iron: IDENTITY_MATRIX,
+        axis_scale: [1.0; 3],
+    };
+
+    /// Apply the calibration to a reading
+    pub fn apply(&self, reading: [f32; 3]) -> [f32; 3] {
+        let mut corrected = [0.0; 3];
+        for (i, val) in corrected.iter_mut().enumerate() {
+            *val = (reading[i] - self.hard_iron[i]) * self.axis_scale[i];
+        }
+        mat_mul_vec(&self.soft_iron, corrected)
+    }
+}
+
+impl Default for Calibration {
+    fn default() -> Self {
+        Self::IDENTITY
+    }
+}
+
+/// Multiply a 3x3 matrix by a vector
+pub(crate) fn mat_mul_vec(m: &[[f32; 3]; 3], v: [f32; 3]) -> [f32; 3] {
+    let mut out = [0.0; 3];
+    for (row, val) in m.iter().zip(out.iter_mut()) {
+        *val = row[0] * v[0] + row[1] * v[1] + row[2] * v[2];
+    }
+    out
+}
+
+/// Whether every element is finite (not NaN or infinite)
+pub(crate) fn all_finite(vals: &[f32]) -> bool {
+    vals.iter().all(|v| v.is_finite())
+}
+
+/// Collects per-axis min/max while the sensor is rotated through all
+/// orientations, to estimate hard-iron offsets and per-axis scale.
+/// Running offsets and coverage are available during collection,
+/// so a UI can prompt the user to keep rotating.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CalibrationCollector {
+    min: [f32; 3],
+    max: [f32; 3],
+    count: u32,
+}
+
+impl CalibrationCollector {
+    pub fn new() -> Self {
+        Self {
+            min: [f32::INFINITY; 3],
+            max: [f32::NEG_INFINITY; 3],
+            count: 0,
+        }
+    }
+
+    /// Add a scaled (microtesla) reading
+    pub fn add(&mut self, reading: [f32; 3]) {
+        for (i, val) in reading.iter().enumerate() {
+            self.min[i] = self.min[i].min(*val);
+            self.max[i] = self.max[i].max(*val);
+        }
+        self.count += 1;
+    }
+
+    /// Number of readings collected
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    /// Running hard-iron offset estimate: the midpoint of each axis range
+    pub fn current_offsets(&self) -> [f32; 3] {
+        if self.count == 0 {
+            return [0.0; 3];
+        }
+        let mut offsets = [0.0; 3];
+        for (i, offset) in offsets.iter_mut().enumerate() {
+            *offset = (self.max[i] + self.min[i]) / 2.0;
+        }
+        offsets
+    }
+
+    /// Span (max - min) covered so far on each axis, in microtesla.
+    /// A well-sampled sphere has similar spans of about twice the local
+    /// field strength on every axis.
+    pub fn coverage(&self) -> [f32; 3] {
+        if self.count == 0 {
+            return [0.0; 3];
+        }
+        let mut spans = [0.0; 3];
+        for (i, span) in spans.iter_mut().enumerate() {
+            *span = self.max[i] - self.min[i];
+        }
+        spans
+    }
+
+    /// Compute the calibration: hard-iron offsets from the axis midpoints,
+    /// and axis scales that equalize the spans.
+    /// Returns `None` if any axis has no span yet.
+    pub fn finish(&self) -> Option<Calibration> {
+        let spans = self.coverage();
+        if spans.iter().any(|span| *span <= 0.0) {
+            return None;
+        }
+        let avg_span = (spans[0] + spans[1] + spans[2]) / 3.0;
+        let mut axis_scale = [1.0; 3];
+        for (scale, span) in axis_scale.iter_mut().zip(spans.iter()) {
+            *scale = avg_span / span;
+        }
+        Some(Calibration {
+            hard_iron: self.current_offsets(),
+            soft_iron: IDENTITY_MATRIX,
+            axis_scale,
+        })
+    }
+}
+
+impl Default for CalibrationCollector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
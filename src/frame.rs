@@ -0,0 +1,101 @@
+/*
+Copyright (c) 2020 Todd Stellanova
+LICENSE: BSD3 (see LICENSE file)
+*/
+
+//! Compact binary telemetry frames, for streaming readings over a UART
+//!
+//! Each frame is `FRAME_LEN` bytes:
+//!
+//! | Byte | Contents                                              |
+//! |------|-------------------------------------------------------|
+//! | 0    | `FRAME_MAGIC` (0xA5)                                  |
+//! | 1-6  | Mag X, Y, Z as i16 raw counts, big-endian             |
+//! | 7    | Temperature, degrees Celsius as i8; 0x80 if absent    |
+//! | 8    | Status: bit 0 RDY, bit 1 LOCK (as the chip reports)   |
+//! | 9    | Checksum: XOR of bytes 1-8                            |
+
+use crate::Snapshot;
+#[cfg(feature = "embedded-io")]
+use embedded_io::Write;
+
+/// First byte of every frame
+pub const FRAME_MAGIC: u8 = 0xA5;
+
+/// Length of a frame in bytes
+pub const FRAME_LEN: usize = 10;
+
+/// Temperature byte sent when the snapshot has no temperature
+pub const FRAME_NO_TEMPERATURE: u8 = 0x80;
+
+/// Encode a snapshot as a frame
+pub fn encode_frame(snapshot: &Snapshot) -> [u8; FRAME_LEN] {
+    let mut frame = [0u8; FRAME_LEN];
+    frame[0] = FRAME_MAGIC;
+    for (i, val) in snapshot.mag.iter().enumerate() {
+        let bytes = val.to_be_bytes();
+        frame[1 + 2 * i] = bytes[0];
+        frame[2 + 2 * i] = bytes[1];
+    }
+    frame[7] = match snapshot.temperature {
+        // clamp to the i8 range, keeping -128 (0x80) for "absent"
+        Some(celsius) => celsius.clamp(-127, 127) as i8 as u8,
+        None => FRAME_NO_TEMPERATURE,
+    };
+    frame[8] = (snapshot.status.data_ready as u8)
+        | ((snapshot.status.locked as u8) << 1);
+    frame[9] = frame[1..9].iter().fold(0, |acc, b| acc ^ b);
+    frame
+}
+
+/// Write a snapshot as one frame to a byte sink, such as a UART
+#[cfg(feature = "embedded-io")]
+pub fn stream_to<W: Write>(
+    sink: &mut W,
+    snapshot: &Snapshot,
+) -> Result<(), W::Error> {
+    sink.write_all(&encode_frame(snapshot))?;
+    sink.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{GainSetting, OdrSetting, Status};
+
+    const SNAPSHOT: Snapshot = Snapshot {
+        mag: [0x0123, -2, 0x7F00],
+        temperature: Some(-5),
+        status: Status {
+            data_ready: true,
+            locked: false,
+        },
+        gain: GainSetting::Gain0820,
+        odr: OdrSetting::Odr30_0Hz,
+    };
+
+    /// `SNAPSHOT` as a frame: checksum 0x01^0x23^0xFF^0xFE^0x7F^0x00^0xFB^0x01
+    const SNAPSHOT_FRAME: [u8; FRAME_LEN] =
+        [0xA5, 0x01, 0x23, 0xFF, 0xFE, 0x7F, 0x00, 0xFB, 0x01, 0xA6];
+
+    #[test]
+    fn frame_bytes() {
+        assert_eq!(encode_frame(&SNAPSHOT), SNAPSHOT_FRAME);
+
+        let absent = Snapshot {
+            temperature: None,
+            ..SNAPSHOT
+        };
+        assert_eq!(encode_frame(&absent)[7], FRAME_NO_TEMPERATURE);
+    }
+
+    #[cfg(feature = "embedded-io")]
+    #[test]
+    fn stream_writes_one_frame() {
+        let mut buf = [0u8; 2 * FRAME_LEN];
+        let mut sink = &mut buf[..];
+        stream_to(&mut sink, &SNAPSHOT).unwrap();
+        assert_eq!(sink.len(), FRAME_LEN);
+        assert_eq!(buf[..FRAME_LEN], SNAPSHOT_FRAME);
+    }
+}
@@ -3,22 +3,70 @@ Copyright (c) 2020 Todd Stellanova
 LICENSE: BSD3 (see LICENSE file)
 */
 
-use crate::interface::SensorInterface;
+use crate::interface::{is_contiguous_span, SensorInterface};
 use crate::Error;
 use embedded_hal as hal;
 
 // #[cfg(feature = "rttdebug")]
 // use panic_rtt_core::rprintln;
 
-const I2C_ADDRESS: u8 = 0x1E;
+/// 7-bit I2C address of the HMC5983 and HMC5883, fixed in silicon:
+/// the chip has no address-select pin.
+pub const I2C_ADDRESS: u8 = 0x1E;
 
+// Over I2C the chip advances its register pointer after each byte read,
+// with two exceptions: after 0x08 (the last data register) it rolls back
+// to 0x03, and after 0x0C it rolls over to 0x00. Block reads that start
+// in the data registers therefore never reach Status (0x09).
+
+/// Bus bit-times to poll Status then read the 6 data bytes:
+/// each transaction is address, register, repeated-start address, then
+/// the data, at 9 bits per byte, plus about 3 bit-times of start/stop.
+/// Status poll: 4 bytes (39 bit-times); data read: 9 bytes (84 bit-times).
+const BIT_TIMES_PER_SAMPLE: u32 = 39 + 84;
+
+// 220 Hz needs at least 27 kHz of bus time; standard-mode I2C has headroom
+const _: () = assert!(I2cInterface::<()>::max_read_hz(100_000) >= 220);
+
+/// I2C connection to the sensor.
+/// `I2C` may be an owned bus or a shared-bus proxy (such as those from the
+/// `shared-bus` crate), since only the blocking embedded-hal traits are used.
 pub struct I2cInterface<I2C> {
     i2c_port: I2C,
+    /// Number of times a failed transaction is retried before giving up.
+    /// Useful on multi-master buses where arbitration loss is transient,
+    /// and for HALs that time out while the chip stretches the clock (as it
+    /// may briefly after a mode change): the retry usually succeeds.
+    /// embedded-hal does not classify errors, so every error is retried;
+    /// implement `ClassifyBusError` to tell timeouts apart in application
+    /// code. If the HAL has a configurable timeout, allow at least one
+    /// measurement time (6 ms) so a stretched transaction isn't cut short.
+    pub max_retries: u8,
+    /// Read registers with a repeated start (`write_read`, the default).
+    /// Set to false to send a separate write then read, with a stop in
+    /// between, for HALs that mishandle repeated starts.
+    pub use_repeated_start: bool,
 }
 
 impl<I2C> I2cInterface<I2C> {
     pub fn new(i2c_port: I2C) -> Self {
-        Self { i2c_port }
+        Self {
+            i2c_port,
+            max_retries: 0,
+            use_repeated_start: true,
+        }
+    }
+
+    /// Consume the interface and return the I2C bus
+    pub fn release(self) -> I2C {
+        self.i2c_port
+    }
+
+    /// Maximum sample rate the bus can sustain at `bus_hz` when polling
+    /// Status and reading the data each sample, ignoring software overhead.
+    /// Choose an ODR below this, or the output registers will lock.
+    pub const fn max_read_hz(bus_hz: u32) -> u32 {
+        bus_hz / BIT_TIMES_PER_SAMPLE
     }
 }
 
@@ -41,10 +89,18 @@ where
         // #[cfg(feature = "rttdebug")]
         // rprintln!("write: {:?}",&write_buf);
 
-        self.i2c_port
-            .write(I2C_ADDRESS, &write_buf)
-            .map_err(Error::Comm)?;
-        Ok(())
+        let mut attempts = 0;
+        loop {
+            match self.i2c_port.write(I2C_ADDRESS, &write_buf) {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    if attempts >= self.max_retries {
+                        return Err(Error::Comm(e));
+                    }
+                    attempts += 1;
+                }
+            }
+        }
     }
 
     fn read_block(
@@ -52,16 +108,86 @@ where
         reg: u8,
         recv_buf: &mut [u8],
     ) -> Result<(), Self::InterfaceError> {
+        if recv_buf.is_empty() || !is_contiguous_span(reg, recv_buf.len()) {
+            return Err(Error::InvalidLength);
+        }
         // #[cfg(feature = "rttdebug")]
         // rprintln!("read_block: 0x{:0x} [{}]", reg, recv_buf.len());
 
         let cmd_buf = [reg];
-        self.i2c_port
-            .write_read(I2C_ADDRESS, &cmd_buf, recv_buf)
-            .map_err(Error::Comm)?;
+        let mut attempts = 0;
+        loop {
+            let rc = if self.use_repeated_start {
+                self.i2c_port.write_read(I2C_ADDRESS, &cmd_buf, recv_buf)
+            } else {
+                self.i2c_port
+                    .write(I2C_ADDRESS, &cmd_buf)
+                    .and_then(|_| self.i2c_port.read(I2C_ADDRESS, recv_buf))
+            };
+            match rc {
+                Ok(()) => break,
+                Err(e) => {
+                    if attempts >= self.max_retries {
+                        return Err(Error::Comm(e));
+                    }
+                    attempts += 1;
+                }
+            }
+        }
 
         // #[cfg(feature = "rttdebug")]
         // rprintln!("recv_buf: {:?}", &recv_buf);
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An I2C bus that counts transactions and reads zeros
+    #[derive(Default)]
+    struct CountingI2c {
+        transactions: usize,
+    }
+
+    impl hal::blocking::i2c::Write for CountingI2c {
+        type Error = ();
+        fn write(&mut self, _addr: u8, _bytes: &[u8]) -> Result<(), ()> {
+            self.transactions += 1;
+            Ok(())
+        }
+    }
+
+    impl hal::blocking::i2c::Read for CountingI2c {
+        type Error = ();
+        fn read(&mut self, _addr: u8, _buf: &mut [u8]) -> Result<(), ()> {
+            self.transactions += 1;
+            Ok(())
+        }
+    }
+
+    impl hal::blocking::i2c::WriteRead for CountingI2c {
+        type Error = ();
+        fn write_read(
+            &mut self,
+            _addr: u8,
+            _bytes: &[u8],
+            _buf: &mut [u8],
+        ) -> Result<(), ()> {
+            self.transactions += 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn zero_length_read_is_rejected_without_bus_traffic() {
+        let mut iface = I2cInterface::new(CountingI2c::default());
+        let rc = iface.read_block(0x03, &mut []);
+        assert!(matches!(rc, Err(Error::InvalidLength)));
+        assert_eq!(iface.i2c_port.transactions, 0);
+
+        iface.read_block(0x03, &mut [0; 1]).unwrap();
+        assert_eq!(iface.i2c_port.transactions, 1);
+    }
+}
@@ -9,14 +9,54 @@ pub use self::spi::SpiInterface;
 pub mod i2c;
 pub use self::i2c::I2cInterface;
 
-/// A method of communicating with the device
+#[cfg(any(test, feature = "sim"))]
+pub mod sim;
+#[cfg(any(test, feature = "sim"))]
+pub use self::sim::{SimClock, SimInterface};
+
+#[cfg(any(test, feature = "sim"))]
+pub mod recording;
+#[cfg(any(test, feature = "sim"))]
+pub use self::recording::RecordingInterface;
+
+/// Registers readable in one block: Config A through ID C (0x00..=0x0C),
+/// and the temperature output (0x31..=0x32). The addresses between are
+/// unimplemented, and auto-increment across them is undefined.
+const CONTIGUOUS_SPANS: [(u8, u8); 2] = [(0x00, 0x0C), (0x31, 0x32)];
+
+/// Whether `len` registers from `reg` lie within one contiguous span,
+/// so that a block read of them returns only defined registers
+pub(crate) fn is_contiguous_span(reg: u8, len: usize) -> bool {
+    let last = reg as usize + len.saturating_sub(1);
+    CONTIGUOUS_SPANS.iter().any(|(first, end)| {
+        reg >= *first && reg <= *end && last <= *end as usize
+    })
+}
+
+/// A method of communicating with the device.
+/// Transactions must complete in the order they are issued: the driver
+/// reads back registers after writing them and expects to see the written
+/// value. The blocking `embedded-hal` traits guarantee this; an interface
+/// over a queued or DMA-backed bus must override `flush`.
 pub trait SensorInterface {
     /// Interface associated error type
     type InterfaceError;
 
+    /// Whether a block read continuing past the last data register (0x08)
+    /// returns Status (0x09). True over SPI; over I2C the register pointer
+    /// rolls back to 0x03 instead.
+    const STATUS_FOLLOWS_DATA: bool = false;
+
+    /// Whether this is an SPI interface, for SPI-specific diagnostics
+    const IS_SPI: bool = false;
+
     /// Read a block from a specific register
     /// `reg`: The register address to read from
     /// `recv_buf`: The buffer to receive into
+    /// An empty `recv_buf` is rejected with `Error::InvalidLength`,
+    /// since some HALs fail zero-length reads, as is one longer than the
+    /// interface can transfer at once, or one that would run outside the
+    /// contiguous register spans 0x00..=0x0C and 0x31..=0x32.
     fn read_block(
         &mut self,
         reg: u8,
@@ -29,4 +69,12 @@ pub trait SensorInterface {
         reg: u8,
         val: u8,
     ) -> Result<(), Self::InterfaceError>;
+
+    /// Wait until all previously issued writes have completed on the bus.
+    /// The driver calls this before reading back a register it just wrote.
+    /// Blocking interfaces complete each write before returning, so the
+    /// default does nothing.
+    fn flush(&mut self) -> Result<(), Self::InterfaceError> {
+        Ok(())
+    }
 }
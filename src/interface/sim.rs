@@ -0,0 +1,360 @@
+/*
+Copyright (c) 2020 Todd Stellanova
+LICENSE: BSD3 (see LICENSE file)
+*/
+
+use core::cell::Cell;
+
+use crate::interface::{is_contiguous_span, SensorInterface};
+use crate::{OdrSetting, Register};
+use embedded_hal as hal;
+use hal::blocking::delay::{DelayMs, DelayUs};
+
+/// Number of register addresses served by the simulator (0x00..=0x32)
+const SIM_REGISTER_COUNT: usize = Register::TempLsb as usize + 1;
+
+/// Number of register writes kept in the write log
+pub const SIM_WRITE_LOG_LEN: usize = 64;
+
+/// Status register: data ready
+const STATUS_RDY: u8 = 1 << 0;
+
+/// Config C (Mode register) MD1:MD0 bits
+const MODE_BITS: u8 = 0b11;
+const MODE_CONTINUOUS: u8 = 0b00;
+const MODE_SINGLE: u8 = 0b01;
+const MODE_IDLE: u8 = 0b10;
+
+/// Bits of `data_read_mask` once all six data registers have been read
+const ALL_DATA_READ: u8 = 0b11_1111;
+
+/// Time for one conversion (datasheet: 6 ms)
+const CONVERSION_US: u64 = 6_000;
+
+/// Output rates indexed by the Config A DO2:DO0 field
+const ODR_BY_FIELD: [OdrSetting; 8] = [
+    OdrSetting::Odr0_75Hz,
+    OdrSetting::Odr1_5Hz,
+    OdrSetting::Odr3_0Hz,
+    OdrSetting::Odr7_5Hz,
+    OdrSetting::Odr15_0Hz,
+    OdrSetting::Odr30_0Hz,
+    OdrSetting::Odr75_0Hz,
+    OdrSetting::Odr220_0Hz,
+];
+
+/// Simulated time in microseconds, shared by a `SimInterface` and the
+/// delay source given to the driver, so that samples arrive as the driver
+/// waits. Delays are implemented on `&SimClock`.
+#[derive(Debug, Default)]
+pub struct SimClock {
+    now_us: Cell<u64>,
+}
+
+impl SimClock {
+    pub const fn new() -> Self {
+        Self {
+            now_us: Cell::new(0),
+        }
+    }
+
+    /// Microseconds elapsed since the clock was created
+    pub fn now_us(&self) -> u64 {
+        self.now_us.get()
+    }
+
+    /// Let time pass
+    pub fn advance_us(&self, us: u64) {
+        self.now_us.set(self.now_us.get() + us);
+    }
+}
+
+impl DelayMs<u8> for &SimClock {
+    fn delay_ms(&mut self, ms: u8) {
+        self.advance_us(ms as u64 * 1000);
+    }
+}
+
+impl DelayUs<u16> for &SimClock {
+    fn delay_us(&mut self, us: u16) {
+        self.advance_us(us as u64);
+    }
+}
+
+/// A simulated sensor for host testing without hardware.
+/// Serves a register map, logs register writes (see `writes`), and
+/// replays a scripted sequence of mag vectors (X, Y, Z) as the chip's
+/// measurements.
+///
+/// Measurements follow Config C: continuous mode measures at the output
+/// rate in Config A, single mode measures once after the averaging time
+/// then returns to idle. Each measurement loads the next scripted sample
+/// into the data registers and sets Status RDY, which stays set until all
+/// six data registers have been read. The first measurement after a
+/// Config B write repeats the previous output instead, as on the chip,
+/// where a new gain takes effect from the second measurement. Once the
+/// script is exhausted no further samples become ready.
+///
+/// Without a clock the chip is infinitely fast: a measurement that is due
+/// completes as soon as the previous sample has been read. With a
+/// `SimClock` (see `new_with_clock`) measurements complete as simulated
+/// time passes, and bus transactions can be made to take time
+/// (`set_bus_hz`).
+pub struct SimInterface<'a> {
+    registers: [u8; SIM_REGISTER_COUNT],
+    samples: &'a [[i16; 3]],
+    next_sample: usize,
+    clock: Option<&'a SimClock>,
+    /// When the next measurement completes, if one is under way
+    next_measurement_us: Option<u64>,
+    /// The next measurement repeats the previous output
+    gain_changed: bool,
+    /// Data registers read since the last measurement, one bit each
+    data_read_mask: u8,
+    missed_samples: usize,
+    bus_hz: u32,
+    write_log: [(u8, u8); SIM_WRITE_LOG_LEN],
+    write_count: usize,
+}
+
+impl<'a> SimInterface<'a> {
+    /// A simulated HMC5983 with power-on register defaults
+    pub fn new() -> Self {
+        Self::new_with_samples(&[])
+    }
+
+    /// A simulated HMC5983 that replays `samples` in order
+    pub fn new_with_samples(samples: &'a [[i16; 3]]) -> Self {
+        let mut registers = [0; SIM_REGISTER_COUNT];
+        registers[Register::ConfigA as usize] = 0x10;
+        registers[Register::ConfigB as usize] = 0x20;
+        registers[Register::ConfigC as usize] = 0x01;
+        registers[Register::IdA as usize] = b'H';
+        registers[Register::IdB as usize] = b'4';
+        registers[Register::IdC as usize] = b'3';
+        Self {
+            registers,
+            samples,
+            next_sample: 0,
+            clock: None,
+            next_measurement_us: None,
+            gain_changed: false,
+            data_read_mask: 0,
+            missed_samples: 0,
+            bus_hz: 0,
+            write_log: [(0, 0); SIM_WRITE_LOG_LEN],
+            write_count: 0,
+        }
+    }
+
+    /// A simulated HMC5983 that replays `samples` in order, measuring
+    /// as `clock` advances
+    pub fn new_with_clock(
+        samples: &'a [[i16; 3]],
+        clock: &'a SimClock,
+    ) -> Self {
+        Self {
+            clock: Some(clock),
+            ..Self::new_with_samples(samples)
+        }
+    }
+
+    /// Make each bus transaction take as long as it would on an I2C bus
+    /// clocked at `hz`: 9 bit-times per byte plus start and stop.
+    /// Only has an effect with a clock; zero (the default) makes
+    /// transactions instantaneous.
+    pub fn set_bus_hz(&mut self, hz: u32) {
+        self.bus_hz = hz;
+    }
+
+    /// Set the value of a simulated register
+    pub fn set_register(&mut self, reg: Register, val: u8) {
+        self.registers[reg as usize] = val;
+    }
+
+    /// The current value of a simulated register
+    pub fn register(&self, reg: Register) -> u8 {
+        self.registers[reg as usize]
+    }
+
+    /// Register writes as `(address, value)`, oldest first.
+    /// Only the first `SIM_WRITE_LOG_LEN` writes are kept.
+    pub fn writes(&self) -> &[(u8, u8)] {
+        &self.write_log[..self.write_count.min(SIM_WRITE_LOG_LEN)]
+    }
+
+    /// Empty the write log
+    pub fn clear_writes(&mut self) {
+        self.write_count = 0;
+    }
+
+    /// Number of scripted samples that have been measured
+    pub fn samples_measured(&self) -> usize {
+        self.next_sample
+    }
+
+    /// Number of measurements that replaced a sample before all of its
+    /// data registers were read, i.e. samples the driver missed
+    pub fn missed_samples(&self) -> usize {
+        self.missed_samples
+    }
+
+    /// Charge the clock for a transaction of `bytes` on the bus
+    fn charge_bus_time(&self, bytes: usize) {
+        if let (Some(clock), true) = (self.clock, self.bus_hz > 0) {
+            let bit_times = (bytes as u64) * 9 + 3;
+            let us = (bit_times * 1_000_000).div_ceil(self.bus_hz as u64);
+            clock.advance_us(us);
+        }
+    }
+
+    /// Duration of one output period at the configured rate
+    fn period_us(&self) -> u64 {
+        let field = (self.registers[Register::ConfigA as usize] >> 2) & 0b111;
+        ODR_BY_FIELD[field as usize].period_us() as u64
+    }
+
+    /// Duration of a single measurement with the configured averaging
+    fn single_measurement_us(&self) -> u64 {
+        let field = (self.registers[Register::ConfigA as usize] >> 5) & 0b11;
+        CONVERSION_US << field
+    }
+
+    fn now_us(&self) -> u64 {
+        self.clock.map_or(0, SimClock::now_us)
+    }
+
+    fn data_ready(&self) -> bool {
+        self.registers[Register::Status as usize] & STATUS_RDY != 0
+    }
+
+    /// Complete any measurements that are due
+    fn update(&mut self) {
+        while let Some(due) = self.next_measurement_us {
+            let complete = match self.clock {
+                Some(clock) => clock.now_us() >= due,
+                // an infinitely fast chip measures once the last sample
+                // has been read
+                None => !self.data_ready(),
+            };
+            if !complete {
+                break;
+            }
+            self.measure();
+
+            let mode = &mut self.registers[Register::ConfigC as usize];
+            if *mode & MODE_BITS == MODE_SINGLE {
+                *mode = (*mode & !MODE_BITS) | MODE_IDLE;
+                self.next_measurement_us = None;
+            } else {
+                self.next_measurement_us = Some(due + self.period_us());
+            }
+            if self.clock.is_none() {
+                break;
+            }
+        }
+    }
+
+    /// Take one measurement into the data registers
+    fn measure(&mut self) {
+        if self.gain_changed {
+            self.gain_changed = false;
+        } else if let Some(sample) = self.samples.get(self.next_sample) {
+            let regs = [Register::DataX, Register::DataY, Register::DataZ];
+            for (reg, val) in regs.iter().zip(sample.iter()) {
+                let bytes = val.to_be_bytes();
+                self.registers[*reg as usize] = bytes[0];
+                self.registers[*reg as usize + 1] = bytes[1];
+            }
+            self.next_sample += 1;
+        } else {
+            return;
+        }
+        if self.data_ready() {
+            self.missed_samples += 1;
+        }
+        self.registers[Register::Status as usize] |= STATUS_RDY;
+        self.data_read_mask = 0;
+    }
+
+    /// Start measuring as selected by a Config C write
+    fn start_mode(&mut self, val: u8) {
+        let now = self.now_us();
+        self.next_measurement_us = match val & MODE_BITS {
+            MODE_CONTINUOUS => Some(now + self.period_us()),
+            MODE_SINGLE => Some(now + self.single_measurement_us()),
+            _ => None,
+        };
+    }
+}
+
+impl Default for SimInterface<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SensorInterface for SimInterface<'_> {
+    type InterfaceError = crate::Error<(), ()>;
+
+    fn read_block(
+        &mut self,
+        reg: u8,
+        recv_buf: &mut [u8],
+    ) -> Result<(), Self::InterfaceError> {
+        if recv_buf.is_empty() || !is_contiguous_span(reg, recv_buf.len()) {
+            return Err(crate::Error::InvalidLength);
+        }
+        // address, register, repeated-start address, then the data
+        self.charge_bus_time(3 + recv_buf.len());
+        self.update();
+
+        // mimic the I2C pointer rolling back from the last data register
+        let data_start = Register::DataX as usize;
+        let data_last = Register::DataY as usize + 1;
+        let mut addr = reg as usize;
+        for byte in recv_buf.iter_mut() {
+            *byte = self.registers[addr];
+            if (data_start..=data_last).contains(&addr) {
+                self.data_read_mask |= 1 << (addr - data_start);
+            }
+            addr = if addr == data_last {
+                data_start
+            } else {
+                addr + 1
+            };
+        }
+        if self.data_read_mask == ALL_DATA_READ {
+            self.registers[Register::Status as usize] &= !STATUS_RDY;
+            self.data_read_mask = 0;
+        }
+        Ok(())
+    }
+
+    fn write_reg(
+        &mut self,
+        reg: u8,
+        val: u8,
+    ) -> Result<(), Self::InterfaceError> {
+        let addr = reg as usize;
+        if addr >= SIM_REGISTER_COUNT {
+            return Err(crate::Error::Comm(()));
+        }
+        // address, register, value
+        self.charge_bus_time(3);
+        self.update();
+
+        if let Some(entry) = self.write_log.get_mut(self.write_count) {
+            *entry = (reg, val);
+        }
+        self.write_count += 1;
+
+        self.registers[addr] = val;
+        if reg == Register::ConfigB.to_addr() {
+            self.gain_changed = true;
+        } else if reg == Register::ConfigC.to_addr() {
+            self.start_mode(val);
+        }
+        Ok(())
+    }
+}
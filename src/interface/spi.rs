@@ -3,9 +3,10 @@ Copyright (c) 2020 Todd Stellanova
 LICENSE: BSD3 (see LICENSE file)
 */
 
-use crate::interface::SensorInterface;
+use crate::interface::{is_contiguous_span, SensorInterface};
 
 use embedded_hal as hal;
+use hal::blocking::delay::DelayUs;
 use hal::digital::v2::OutputPin;
 
 use crate::Error;
@@ -19,38 +20,154 @@ const DIRECTION_READ: u8 = 1 << 7;
 const DIRECTION_WRITE: u8 = 0;
 const MULTI_ADDRESS_INCREMENT: u8 = 1 << 6;
 
-pub struct SpiInterface<SPI, CS> {
+/// Clocks to poll Status (command + 1 byte) then read the data
+/// (command + 6 bytes), at 8 clocks per byte
+const CLOCKS_PER_SAMPLE: u32 = (2 + 7) * 8;
+
+/// SPI connection to the sensor.
+/// `SPI` may be an owned bus or a shared-bus proxy (such as those from the
+/// `shared-bus` crate): chip select is driven by this interface around each
+/// transaction, so other devices on the bus just need their own CS pins.
+/// The transfer buffer `B` is owned (32 bytes) by default; use
+/// `new_with_buffer` to lend one instead.
+pub struct SpiInterface<SPI, CS, D = NoCsDelay, B = [u8; TRANSFER_BUF_LEN]> {
     spi: SPI,
     cs: CS,
-    transfer_buf: [u8; TRANSFER_BUF_LEN],
+    transfer_buf: B,
+    /// Drive CS high (rather than low) to select the sensor, for wiring
+    /// through an inverting level shifter. Defaults to false.
+    pub cs_active_high: bool,
+    delay: D,
+    cs_setup_us: u8,
+}
+
+/// Delay source for an `SpiInterface` with no CS setup delay
+pub struct NoCsDelay;
+
+impl DelayUs<u8> for NoCsDelay {
+    fn delay_us(&mut self, _us: u8) {}
 }
 
 impl<SPI, CS> SpiInterface<SPI, CS> {
     pub fn new(spi: SPI, cs: CS) -> Self {
+        SpiInterface::new_with_cs_delay(spi, cs, NoCsDelay, 0)
+    }
+}
+
+impl<'a, SPI, CS> SpiInterface<SPI, CS, NoCsDelay, &'a mut [u8]> {
+    /// Create an interface that uses the application's scratch buffer for
+    /// transfers rather than embedding its own, so the caller controls
+    /// where that memory lives. Reads can be at most `buf.len() - 1` bytes:
+    /// an 8-byte buffer covers the mag data reads (with Status), while
+    /// `read_config_data_status_raw` and `read_full_state` need 11 bytes.
+    pub fn new_with_buffer(spi: SPI, cs: CS, buf: &'a mut [u8]) -> Self {
+        Self {
+            spi,
+            cs,
+            transfer_buf: buf,
+            cs_active_high: false,
+            delay: NoCsDelay,
+            cs_setup_us: 0,
+        }
+    }
+}
+
+impl<SPI, CS, D> SpiInterface<SPI, CS, D> {
+    /// Create an interface that waits `cs_setup_us` after selecting the
+    /// sensor before the first clock, for boards where the chip needs
+    /// CS to settle (reads are intermittently corrupted otherwise).
+    pub fn new_with_cs_delay(
+        spi: SPI,
+        cs: CS,
+        delay: D,
+        cs_setup_us: u8,
+    ) -> Self {
         Self {
             spi,
             cs,
             transfer_buf: [0; TRANSFER_BUF_LEN],
+            cs_active_high: false,
+            delay,
+            cs_setup_us,
+        }
+    }
+
+    /// Maximum sample rate the bus can sustain at `bus_hz` when polling
+    /// Status and reading the data each sample, ignoring software and
+    /// chip-select overhead.
+    /// Choose an ODR below this, or the output registers will lock.
+    pub const fn max_read_hz(bus_hz: u32) -> u32 {
+        bus_hz / CLOCKS_PER_SAMPLE
+    }
+}
+
+impl<SPI, CS, D, B> SpiInterface<SPI, CS, D, B> {
+    /// Consume the interface and return the SPI bus and chip select pin.
+    /// The CS delay source and transfer buffer are dropped.
+    pub fn release(self) -> (SPI, CS) {
+        (self.spi, self.cs)
+    }
+}
+
+impl<SPI, CS, D, B, PinE> SpiInterface<SPI, CS, D, B>
+where
+    CS: OutputPin<Error = PinE>,
+    D: DelayUs<u8>,
+{
+    /// Assert chip select, then wait for it to settle
+    fn select<CommE>(&mut self) -> Result<(), Error<CommE, PinE>> {
+        if self.cs_active_high {
+            self.cs.set_high()
+        } else {
+            self.cs.set_low()
+        }
+        .map_err(Error::Pin)?;
+        if self.cs_setup_us > 0 {
+            self.delay.delay_us(self.cs_setup_us);
+        }
+        Ok(())
+    }
+
+    /// Release chip select
+    fn deselect<CommE>(&mut self) -> Result<(), Error<CommE, PinE>> {
+        if self.cs_active_high {
+            self.cs.set_low()
+        } else {
+            self.cs.set_high()
         }
+        .map_err(Error::Pin)
     }
 }
 
-impl<SPI, CS, CommE, PinE> SensorInterface for SpiInterface<SPI, CS>
+impl<SPI, CS, D, B, CommE, PinE> SensorInterface for SpiInterface<SPI, CS, D, B>
 where
     SPI: hal::blocking::spi::Write<u8, Error = CommE>
         + hal::blocking::spi::Transfer<u8, Error = CommE>,
     CS: OutputPin<Error = PinE>,
+    D: DelayUs<u8>,
+    B: AsMut<[u8]>,
     CommE: core::fmt::Debug,
     PinE: core::fmt::Debug,
 {
     type InterfaceError = crate::Error<CommE, PinE>;
 
+    const STATUS_FOLLOWS_DATA: bool = true;
+
+    const IS_SPI: bool = true;
+
     fn read_block(
         &mut self,
         reg: u8,
         recv_buf: &mut [u8],
     ) -> Result<(), Self::InterfaceError> {
-        self.cs.set_low().map_err(Error::Pin)?;
+        // one transfer byte is used by the command
+        if recv_buf.is_empty()
+            || recv_buf.len() >= self.transfer_buf.as_mut().len()
+            || !is_contiguous_span(reg, recv_buf.len())
+        {
+            return Err(Error::InvalidLength);
+        }
+        self.select()?;
 
         // the first byte in SPI receive is garbage
         let total_read_bytes = recv_buf.len() + 1;
@@ -58,27 +175,24 @@ where
         //bit 1: MS bit. When 0 don't increment address: when 1 increment address in multiple read.
         //bit 2-7: address AD(5:0). This is the address field of the indexed register.
 
-        for i in 0..total_read_bytes {
-            self.transfer_buf[i] = 0;
+        let transfer_buf = &mut self.transfer_buf.as_mut()[..total_read_bytes];
+        for byte in transfer_buf.iter_mut() {
+            *byte = 0;
         }
-        self.transfer_buf[0] = reg | DIRECTION_READ | MULTI_ADDRESS_INCREMENT;
+        transfer_buf[0] = reg | DIRECTION_READ | MULTI_ADDRESS_INCREMENT;
 
         let rc = self
             .spi
-            .transfer(self.transfer_buf[..total_read_bytes].as_mut())
+            .transfer(transfer_buf)
+            .map(|read_slice| {
+                recv_buf.copy_from_slice(&read_slice[1..total_read_bytes]);
+                // #[cfg(feature = "rttdebug")]
+                // rprintln!("read_slice: {:?}",read_slice);
+            })
             .map_err(Error::Comm);
         //release SPI bus
-        self.cs.set_high().map_err(Error::Pin)?;
-
-        if rc.is_err() {
-            return Err(rc.unwrap_err());
-        }
-
-        let read_slice = rc.unwrap();
-        recv_buf.copy_from_slice(&read_slice[1..total_read_bytes]);
-
-        // #[cfg(feature = "rttdebug")]
-        // rprintln!("read_slice: {:?}",read_slice);
+        self.deselect()?;
+        rc?;
 
         Ok(())
     }
@@ -88,16 +202,73 @@ where
         reg: u8,
         val: u8,
     ) -> Result<(), Self::InterfaceError> {
-        self.cs.set_low().map_err(Error::Pin)?;
+        self.select()?;
 
         let block = [reg | DIRECTION_WRITE, val];
         let rc = self.spi.write(&block).map_err(Error::Comm);
-        self.cs.set_high().map_err(Error::Pin)?;
+        self.deselect()?;
 
-        if rc.is_err() {
-            return Err(rc.unwrap_err());
-        }
+        rc?;
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An SPI bus that counts transfers and reads zeros
+    #[derive(Default)]
+    struct CountingSpi {
+        transfers: usize,
+    }
+
+    impl hal::blocking::spi::Write<u8> for CountingSpi {
+        type Error = ();
+        fn write(&mut self, _words: &[u8]) -> Result<(), ()> {
+            self.transfers += 1;
+            Ok(())
+        }
+    }
+
+    impl hal::blocking::spi::Transfer<u8> for CountingSpi {
+        type Error = ();
+        fn transfer<'w>(
+            &mut self,
+            words: &'w mut [u8],
+        ) -> Result<&'w [u8], ()> {
+            self.transfers += 1;
+            Ok(words)
+        }
+    }
+
+    /// A chip select pin that counts selections
+    #[derive(Default)]
+    struct CountingPin {
+        selects: usize,
+    }
+
+    impl OutputPin for CountingPin {
+        type Error = ();
+        fn set_low(&mut self) -> Result<(), ()> {
+            self.selects += 1;
+            Ok(())
+        }
+        fn set_high(&mut self) -> Result<(), ()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn zero_length_read_is_rejected_without_bus_traffic() {
+        let mut iface =
+            SpiInterface::new(CountingSpi::default(), CountingPin::default());
+        let rc = iface.read_block(0x03, &mut []);
+        assert!(matches!(rc, Err(Error::InvalidLength)));
+        assert_eq!((iface.spi.transfers, iface.cs.selects), (0, 0));
+
+        iface.read_block(0x03, &mut [0; 1]).unwrap();
+        assert_eq!((iface.spi.transfers, iface.cs.selects), (1, 1));
+    }
+}
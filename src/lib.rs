@@ -8,17 +8,39 @@ LICENSE: BSD3 (see LICENSE file)
 #[cfg(feature = "rttdebug")]
 use panic_rtt_core::rprintln;
 
-use crate::interface::SensorInterface;
+use crate::calibration::{
+    all_finite, mat_mul_vec, Calibration, IDENTITY_MATRIX,
+};
+use crate::interface::{I2cInterface, SensorInterface};
+use crate::window::SampleWindow;
 use embedded_hal as hal;
-use hal::blocking::delay::DelayMs;
+#[cfg(feature = "fixed")]
+use fixed::types::I16F16;
+use hal::blocking::delay::{DelayMs, DelayUs};
+use hal::digital::v2::InputPin;
 
+pub mod accumulator;
+pub mod calibration;
+pub mod frame;
 pub mod interface;
+mod math;
+pub mod threshold;
+pub mod window;
 
-/// Errors in this crate
+#[cfg(test)]
+mod tests;
+
+/// Errors in this crate.
+/// With the `defmt` feature, `Error` implements `defmt::Format` when
+/// `CommE` and `PinE` do.
 #[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum Error<CommE, PinE> {
-    /// Sensor communication error
+    /// Sensor communication error, as returned by a `SensorInterface`
     Comm(CommE),
+    /// Sensor communication error while accessing register `reg`.
+    /// Driver methods report bus failures this way, keeping the address.
+    CommAt { reg: u8, error: CommE },
     /// Pin setting error
     Pin(PinE),
 
@@ -30,10 +52,164 @@ pub enum Error<CommE, PinE> {
 
     /// Unrecognized chip ID
     UnknownChipId,
+
+    /// Over SPI, the chip ID read back all 0x00, all 0xFF, or shifted by
+    /// one bit: check the SPI mode (CPOL/CPHA, mode 3 or 0) and MISO wiring
+    LikelyWrongSpiMode,
+
+    /// Operation not supported by this chip variant
+    Unsupported,
+
+    /// No data became ready before the timeout expired
+    Timeout,
+
+    /// Self-test response failed its limits or drifted out of tolerance
+    SelfTestFailed,
+
+    /// Buffer length is invalid for the requested read
+    InvalidLength,
+
+    /// Data, config and status registers all read as zero,
+    /// which usually indicates a wiring or bus fault
+    LikelyDisconnected,
+}
+
+/// Placeholder for a DRDY pin that is not connected,
+/// for use as `None::<&NoDrdyPin>`
+pub struct NoDrdyPin;
+
+impl InputPin for NoDrdyPin {
+    type Error = core::convert::Infallible;
+
+    fn is_high(&self) -> Result<bool, Self::Error> {
+        Ok(true)
+    }
+
+    fn is_low(&self) -> Result<bool, Self::Error> {
+        Ok(false)
+    }
+}
+
+impl<CommE, PinE> Error<CommE, PinE> {
+    /// Wrap a communication error, for use with `map_err`.
+    /// (A blanket `From<CommE>` impl would conflict with `From<T> for T`.)
+    pub fn from_comm(err: CommE) -> Self {
+        Error::Comm(err)
+    }
+
+    /// Wrap a pin error, for use with `map_err`
+    pub fn from_pin(err: PinE) -> Self {
+        Error::Pin(err)
+    }
+
+    /// Register address whose access failed, if known
+    pub fn register(&self) -> Option<u8> {
+        match self {
+            Error::CommAt { reg, .. } => Some(*reg),
+            _ => None,
+        }
+    }
+
+    /// Attach the register address to a communication error
+    fn at_register(self, reg: u8) -> Self {
+        match self {
+            Error::Comm(error) => Error::CommAt { reg, error },
+            other => other,
+        }
+    }
+}
+
+impl<CommE: ClassifyBusError, PinE> Error<CommE, PinE> {
+    /// Portable category of a communication error, or `None` for errors
+    /// that did not come from the bus
+    pub fn bus_error_kind(&self) -> Option<BusErrorKind> {
+        match self {
+            Error::Comm(e) | Error::CommAt { error: e, .. } => Some(e.kind()),
+            _ => None,
+        }
+    }
+
+    /// Whether this is a bus error that usually clears on retry, such as a
+    /// clock-stretch timeout (see `BusErrorKind::is_retryable`)
+    pub fn is_retryable(&self) -> bool {
+        self.bus_error_kind()
+            .is_some_and(BusErrorKind::is_retryable)
+    }
+}
+
+/// Portable categories of bus errors
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BusErrorKind {
+    /// Device did not acknowledge (I2C NACK)
+    NoAcknowledge,
+    /// Another master won bus arbitration
+    ArbitrationLoss,
+    /// Transaction timed out, e.g. on clock stretching
+    Timeout,
+    /// Bus fault such as a misplaced start or stop condition
+    Bus,
+    /// Data overrun
+    Overrun,
+    /// Any other error
+    Other,
+}
+
+impl BusErrorKind {
+    /// Whether an error of this kind is usually transient,
+    /// so the operation is worth retrying
+    pub fn is_retryable(self) -> bool {
+        matches!(
+            self,
+            BusErrorKind::ArbitrationLoss
+                | BusErrorKind::Timeout
+                | BusErrorKind::Overrun
+        )
+    }
+}
+
+/// Maps a HAL-specific bus error onto a `BusErrorKind`.
+/// embedded-hal 0.2 errors are opaque, so implement this for your HAL's
+/// error type to let application code branch on the category.
+pub trait ClassifyBusError {
+    fn kind(&self) -> BusErrorKind;
+}
+
+impl ClassifyBusError for () {
+    fn kind(&self) -> BusErrorKind {
+        BusErrorKind::Other
+    }
+}
+
+impl ClassifyBusError for core::convert::Infallible {
+    fn kind(&self) -> BusErrorKind {
+        match *self {}
+    }
+}
+
+/// Supported chip variants
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Variant {
+    /// HMC5983: I2C and SPI, with temperature sensor
+    Hmc5983,
+    /// HMC5883(L): I2C only, no temperature sensor
+    Hmc5883,
+}
+
+impl Variant {
+    /// Datasheet temperature conversion, if the variant has a sensor
+    pub fn temperature_scale(self) -> Option<TemperatureScale> {
+        match self {
+            Variant::Hmc5983 => Some(HMC5983_TEMPERATURE_SCALE),
+            Variant::Hmc5883 => None,
+        }
+    }
 }
 
 /// Gain settings ( in LSb/Gauss )
 /// One tesla (T) is equal to 104 gauss
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 #[repr(u8)]
 pub enum GainSetting {
     ///± 0.88 Ga  / 0.73 (mGa/LSb)
@@ -54,7 +230,82 @@ pub enum GainSetting {
     Gain0230 = 0b11100000,
 }
 
+impl GainSetting {
+    /// Range and resolution at this gain, from the datasheet table.
+    /// The scaled reads use these same values.
+    pub fn describe(self) -> GainInfo {
+        let (full_scale_gauss, lsb_per_gauss) = match self {
+            GainSetting::Gain1370 => (0.88, 1370),
+            GainSetting::Gain1090 => (1.30, 1090),
+            GainSetting::Gain0820 => (1.90, 820),
+            GainSetting::Gain0660 => (2.50, 660),
+            GainSetting::Gain0440 => (4.00, 440),
+            GainSetting::Gain0390 => (4.70, 390),
+            GainSetting::Gain0330 => (5.60, 330),
+            GainSetting::Gain0230 => (8.10, 230),
+        };
+        let resolution_mga_per_lsb = 1000.0 / (lsb_per_gauss as f32);
+        GainInfo {
+            full_scale_gauss,
+            lsb_per_gauss,
+            resolution_mga_per_lsb,
+            // 1 gauss = 100 microtesla
+            resolution_ut_per_lsb: resolution_mga_per_lsb / 10.0,
+        }
+    }
+
+    /// Sensor counts per gauss at this gain
+    pub fn lsb_per_gauss(self) -> u16 {
+        self.describe().lsb_per_gauss
+    }
+
+    /// The next gain with a wider range, if any
+    pub fn coarser(self) -> Option<GainSetting> {
+        match self {
+            GainSetting::Gain1370 => Some(GainSetting::Gain1090),
+            GainSetting::Gain1090 => Some(GainSetting::Gain0820),
+            GainSetting::Gain0820 => Some(GainSetting::Gain0660),
+            GainSetting::Gain0660 => Some(GainSetting::Gain0440),
+            GainSetting::Gain0440 => Some(GainSetting::Gain0390),
+            GainSetting::Gain0390 => Some(GainSetting::Gain0330),
+            GainSetting::Gain0330 => Some(GainSetting::Gain0230),
+            GainSetting::Gain0230 => None,
+        }
+    }
+
+    /// The next gain with a finer resolution, if any
+    pub fn finer(self) -> Option<GainSetting> {
+        match self {
+            GainSetting::Gain1370 => None,
+            GainSetting::Gain1090 => Some(GainSetting::Gain1370),
+            GainSetting::Gain0820 => Some(GainSetting::Gain1090),
+            GainSetting::Gain0660 => Some(GainSetting::Gain0820),
+            GainSetting::Gain0440 => Some(GainSetting::Gain0660),
+            GainSetting::Gain0390 => Some(GainSetting::Gain0440),
+            GainSetting::Gain0330 => Some(GainSetting::Gain0390),
+            GainSetting::Gain0230 => Some(GainSetting::Gain0330),
+        }
+    }
+}
+
+/// Range and resolution of a `GainSetting`
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct GainInfo {
+    /// Full-scale field range, ± gauss
+    pub full_scale_gauss: f32,
+    /// Sensor counts per gauss
+    pub lsb_per_gauss: u16,
+    /// Resolution in milligauss per count
+    pub resolution_mga_per_lsb: f32,
+    /// Resolution in microtesla per count
+    pub resolution_ut_per_lsb: f32,
+}
+
 /// Output Data Rate settings in Hz
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 #[repr(u8)]
 pub enum OdrSetting {
     Odr0_75Hz = 0b000,
@@ -62,11 +313,29 @@ pub enum OdrSetting {
     Odr3_0Hz = 0b010,
     Odr7_5Hz = 0b011,
     Odr15_0Hz = 0b100,
-    Odr30_0Hz = 0b110,
+    Odr30_0Hz = 0b101,
+    Odr75_0Hz = 0b110,
     Odr220_0Hz = 0b111,
 }
 
+impl OdrSetting {
+    /// Time between output samples in microseconds
+    pub fn period_us(self) -> u32 {
+        match self {
+            OdrSetting::Odr0_75Hz => 1_333_333,
+            OdrSetting::Odr1_5Hz => 666_667,
+            OdrSetting::Odr3_0Hz => 333_333,
+            OdrSetting::Odr7_5Hz => 133_333,
+            OdrSetting::Odr15_0Hz => 66_667,
+            OdrSetting::Odr30_0Hz => 33_333,
+            OdrSetting::Odr75_0Hz => 13_333,
+            OdrSetting::Odr220_0Hz => 4_545,
+        }
+    }
+}
+
 /// Configuring sample averaging
+#[derive(Clone, Copy, Debug, PartialEq)]
 #[repr(u8)]
 pub enum SampleAvgSetting {
     AvgSamples1 = 0b00,
@@ -76,7 +345,31 @@ pub enum SampleAvgSetting {
     AvgSamples8 = 0b11,
 }
 
-/// Measurement mode settings
+impl SampleAvgSetting {
+    /// Number of samples averaged per output
+    pub fn count(self) -> u8 {
+        match self {
+            SampleAvgSetting::AvgSamples1 => 1,
+            SampleAvgSetting::AvgSamples2 => 2,
+            SampleAvgSetting::AvgSamples4 => 4,
+            SampleAvgSetting::AvgSamples8 => 8,
+        }
+    }
+
+    /// The setting that averages `count` samples, if the chip supports it
+    pub fn from_count(count: u8) -> Option<Self> {
+        match count {
+            1 => Some(SampleAvgSetting::AvgSamples1),
+            2 => Some(SampleAvgSetting::AvgSamples2),
+            4 => Some(SampleAvgSetting::AvgSamples4),
+            8 => Some(SampleAvgSetting::AvgSamples8),
+            _ => None,
+        }
+    }
+}
+
+/// Measurement mode settings: bias applied during measurement (Config A)
+#[derive(Clone, Copy, Debug, PartialEq)]
 #[repr(u8)]
 pub enum MeasurementModeSetting {
     NormalMode = 0b00,
@@ -88,221 +381,2737 @@ pub enum MeasurementModeSetting {
     TemperatureOnly = 0b11,
 }
 
-pub struct HMC5983<SI> {
-    pub(crate) sensor_interface: SI,
-    /// Buffer for reads and writes to the sensor
-    block_buf: [u8; BLOCK_BUF_LEN],
+/// Encode the Config A register value.
+///
+/// | Bit(s) | Field                        |
+/// |--------|------------------------------|
+/// | 7      | CRA7: temperature sensor on  |
+/// | 6:5    | MA1:MA0: samples averaged    |
+/// | 4:2    | DO2:DO0: output data rate    |
+/// | 1:0    | MS1:MS0: measurement mode    |
+pub const fn encode_config_a(
+    mode: MeasurementModeSetting,
+    odr: OdrSetting,
+    averaging: SampleAvgSetting,
+    temp_enabled: bool,
+) -> u8 {
+    (if temp_enabled { 1 << 7 } else { 0 })
+        | ((averaging as u8) << 5)
+        | ((odr as u8) << 2)
+        | (mode as u8)
 }
 
-impl<SI, CommE, PinE> HMC5983<SI>
-where
-    SI: SensorInterface<InterfaceError = crate::Error<CommE, PinE>>,
-{
-    pub fn new_with_interface(sensor_interface: SI) -> Self {
+/// A magnetometer sample: raw counts for the X, Y, Z axes, in
+/// `CoordinateFrame::Sensor` unless an axis remap has been set
+pub type MagReading = [i16; 3];
+
+/// Coordinate frames that readings can be expressed in.
+/// All are right-handed; `axes` gives the direction of each positive axis.
+/// A positive reading on an axis means the field points along that axis.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CoordinateFrame {
+    /// The chip's own axes, as marked on the package (and on most breakout
+    /// boards): +X along the X arrow, +Y along the Y arrow (90° counter-
+    /// clockwise from X seen from above), +Z up out of the top of the package.
+    /// Mounted face up with X pointing forward, this is forward-left-up.
+    Sensor,
+    /// North-East-Down, as produced by `to_ned`
+    Ned,
+    /// East-North-Up, as produced by `to_enu`
+    Enu,
+}
+
+impl CoordinateFrame {
+    /// Direction of the positive X, Y and Z axes
+    pub const fn axes(self) -> [&'static str; 3] {
+        match self {
+            CoordinateFrame::Sensor => [
+                "X arrow on the package",
+                "Y arrow on the package",
+                "up, out of the top of the package",
+            ],
+            CoordinateFrame::Ned => ["north", "east", "down"],
+            CoordinateFrame::Enu => ["east", "north", "up"],
+        }
+    }
+}
+
+/// Per-sample indicators returned alongside a reading
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SampleFlags {
+    /// Axis measurement overflowed the ADC range (per reported axis)
+    pub saturated: [bool; 3],
+    /// A new sample was ready (Status RDY) when the reading was taken
+    pub data_ready: bool,
+}
+
+/// Response of `get_mag_vector` to saturated readings
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RangePolicy {
+    /// Fail the read with `Error::OutOfRange`
+    Error,
+    /// Limit each axis to the valid output range (-2048..=2047).
+    /// An overflowed axis doesn't report its sign, so the sign of its
+    /// clamped value is meaningless.
+    Clamp,
+    /// Return the values as read, with overflowed axes at -4096
+    PassThrough,
+}
+
+/// Handling of output registers locked by an interrupted partial read.
+/// While locked, the chip does not update the data registers, so reads
+/// return a stale sample until all six data bytes have been read.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LockStrategy {
+    /// Read whatever is in the data registers
+    Ignore,
+    /// Release the lock with a full read, then wait for a fresh sample
+    ClearAndWait,
+}
+
+/// Conversion from raw temperature output to degrees Celsius:
+/// `raw / lsb_per_degree + offset_celsius`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TemperatureScale {
+    /// Counts per degree Celsius; must be positive
+    pub lsb_per_degree: i16,
+    /// Temperature in Celsius at a raw reading of zero
+    pub offset_celsius: i16,
+}
+
+/// HMC5983 datasheet: Temperature = (MSB * 2^8 + LSB) / (2^4 * 8) + 25 C
+pub const HMC5983_TEMPERATURE_SCALE: TemperatureScale = TemperatureScale {
+    lsb_per_degree: 128,
+    offset_celsius: 25,
+};
+
+/// Status register flags
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Status {
+    /// RDY: new data is ready in the output registers
+    pub data_ready: bool,
+    /// LOCK: output registers locked by a partial read
+    pub locked: bool,
+}
+
+impl Status {
+    /// Decode a raw Status register value, such as one captured or
+    /// forwarded from another device. Reserved bits are ignored.
+    pub const fn decode_status(raw: u8) -> Self {
         Self {
-            sensor_interface,
-            block_buf: [0; BLOCK_BUF_LEN],
+            data_ready: (raw & STATUS_RDY) != 0,
+            locked: (raw & STATUS_LOCK) != 0,
         }
     }
+}
 
-    pub fn init(
-        &mut self,
-        delay_source: &mut impl DelayMs<u8>,
-    ) -> Result<(), crate::Error<CommE, PinE>> {
-        self.reset(delay_source)
+/// Sensor state captured together for logging
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Snapshot {
+    /// Mag vector, raw counts
+    pub mag: [i16; 3],
+    /// Temperature in degrees Celsius, if the variant has a sensor
+    pub temperature: Option<i16>,
+    /// Status flags read just before the mag data
+    pub status: Status,
+    /// Active gain
+    pub gain: GainSetting,
+    /// Active output data rate
+    pub odr: OdrSetting,
+}
+
+/// Complete device state returned by `read_full_state`
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct FullState {
+    /// Config A register
+    pub config_a: u8,
+    /// Config B register
+    pub config_b: u8,
+    /// Config C (Mode) register
+    pub config_c: u8,
+    /// Mag vector, raw counts in the sensor frame (not remapped)
+    pub mag: [i16; 3],
+    /// Status flags
+    pub status: Status,
+    /// Temperature in degrees Celsius, if the variant has a sensor
+    pub temperature: Option<i16>,
+}
+
+/// Result of the positive-bias self-test
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SelfTestResult {
+    /// Raw self-test reading per sensor axis
+    pub reading: [i16; 3],
+    /// Whether each sensor axis fell within the datasheet limits
+    pub passed: [bool; 3],
+}
+
+impl SelfTestResult {
+    /// Whether every axis passed
+    pub fn all_passed(&self) -> bool {
+        self.passed.iter().all(|p| *p)
     }
+}
 
-    fn reset(
-        &mut self,
-        delay_source: &mut impl DelayMs<u8>,
-    ) -> Result<(), crate::Error<CommE, PinE>> {
-        //wakeup the chip
-        for reg in 0x00..0x0D {
-            let _val = self.read_reg(reg)?;
-            #[cfg(feature = "rttdebug")]
-            rprintln!("0x{:0x} : {} ", reg, _val);
-        }
+/// Sensor health summary returned by `read_health`
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Health {
+    /// Status flags
+    pub status: Status,
+    /// Whether each sensor axis saturated in the most recent sample read
+    /// through the driver. Cached: `read_health` does not read the data.
+    pub saturated: [bool; 3],
+    /// Most recent self-test result, if a self-test has run
+    pub self_test: Option<SelfTestResult>,
+}
 
-        const EXPECTED_PROD_ID_A: u8 = 72; //'H';
-        const EXPECTED_PROD_ID_B: u8 = 52; //'4';
-        const EXPECTED_PROD_ID_C: u8 = 51; //'3';
-                                           //compare product ID against known product ID
-                                           //read the product identifiers
-        self.sensor_interface
-            .read_block(REG_ID_A, &mut self.block_buf[..3])?;
-        if self.block_buf[0] != EXPECTED_PROD_ID_A
-            || self.block_buf[1] != EXPECTED_PROD_ID_B
-            || self.block_buf[2] != EXPECTED_PROD_ID_C
-        {
-            #[cfg(feature = "rttdebug")]
-            rprintln!(
-                "bad ID block: {},{},{}",
-                self.block_buf[0],
-                self.block_buf[1],
-                self.block_buf[2]
-            );
+impl Health {
+    /// No axis saturated and the last self-test (if any) passed
+    pub fn is_ok(&self) -> bool {
+        !self.saturated.iter().any(|s| *s)
+            && self.self_test.iter().all(|t| t.all_passed())
+    }
+}
 
-            return Err(Error::UnknownChipId);
-        }
+/// Result of the combined positive and negative bias self-test
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SelfTestReport {
+    /// Positive-bias result, limits as for `self_test`
+    pub positive: SelfTestResult,
+    /// Negative-bias result, with the limits negated
+    pub negative: SelfTestResult,
+    /// Whether the positive and negative responses of each sensor axis
+    /// agree in magnitude to within `SELF_TEST_SYMMETRY_PERCENT`
+    pub symmetric: [bool; 3],
+}
 
-        self.set_all_config_a(
-            MeasurementModeSetting::NormalMode,
-            OdrSetting::Odr30_0Hz,
-            SampleAvgSetting::AvgSamples8,
-            true,
-        )?;
+impl SelfTestReport {
+    /// Whether every axis passed both bias tests and was symmetric
+    pub fn all_passed(&self) -> bool {
+        self.positive.all_passed()
+            && self.negative.all_passed()
+            && self.symmetric.iter().all(|s| *s)
+    }
+}
 
-        self.set_gain(GainSetting::Gain0820)?;
-        // (Continuous-measurement mode)
-        self.sensor_interface.write_reg(
-            REG_CONFIG_C,
-            MeasurementModeSetting::NormalMode as u8,
-        )?;
-        delay_source.delay_ms(100);
+/// Bring-up report returned by `init_with_report`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct InitReport {
+    /// Identification registers A, B, C
+    pub chip_id: [u8; 3],
+    /// Self-test result
+    pub self_test: SelfTestResult,
+    /// Config A value read back after initialization
+    pub config_a: u8,
+    /// Config B value read back after initialization
+    pub config_b: u8,
+    /// Config C (Mode register) value read back after initialization
+    pub config_c: u8,
+}
 
-        Ok(())
+/// Why `HMC5983::auto_detect` found no usable sensor
+#[derive(Debug)]
+pub enum DetectFailure<CommE> {
+    /// Nothing acknowledged at `interface::i2c::I2C_ADDRESS`;
+    /// holds the bus error
+    NoResponse(CommE),
+    /// A device acknowledged, but its ID registers (A, B, C) didn't match
+    UnknownDevice([u8; 3]),
+    /// The sensor was found, but configuring it failed
+    InitFailed(Error<CommE, ()>),
+}
+
+/// Error returned by `HMC5983::auto_detect`: what was found on the bus,
+/// and the bus itself, so that it can be used for something else
+pub struct DetectError<CommE, I2C> {
+    failure: DetectFailure<CommE>,
+    i2c: I2C,
+}
+
+impl<CommE, I2C> DetectError<CommE, I2C> {
+    /// Why no usable sensor was found
+    pub fn failure(&self) -> &DetectFailure<CommE> {
+        &self.failure
     }
 
-    /// Set the mag gain, which determines the range
-    pub fn set_gain(
-        &mut self,
-        gain: GainSetting,
-    ) -> Result<(), crate::Error<CommE, PinE>> {
-        let gain_val: u8 = gain as u8;
-        self.sensor_interface.write_reg(REG_CONFIG_B, gain_val)?;
+    /// Return the I2C bus
+    pub fn release(self) -> I2C {
+        self.i2c
+    }
 
-        let confirm_val = self.read_reg(REG_CONFIG_B)?;
-        if confirm_val != gain_val {
-            #[cfg(feature = "rttdebug")]
-            rprintln!("gain bad: expected {} got {}", gain_val, confirm_val);
-            return Err(Error::Configuration);
-        }
-        Ok(())
+    /// Split into the failure and the I2C bus
+    pub fn into_parts(self) -> (DetectFailure<CommE>, I2C) {
+        (self.failure, self.i2c)
     }
+}
 
-    /// Set all of the Config A register settings
-    pub fn set_all_config_a(
-        &mut self,
-        mode: MeasurementModeSetting,
-        odr: OdrSetting,
-        averaging: SampleAvgSetting,
-        temp_enabled: bool,
-    ) -> Result<(), crate::Error<CommE, PinE>> {
-        let new_val = (if temp_enabled { (1 << 7) } else { 0 })
-            & ((averaging as u8) << 6)
-            & ((odr as u8) << 4)
-            & ((mode as u8) << 2);
-        self.sensor_interface.write_reg(REG_CONFIG_A, new_val)
+impl<CommE: core::fmt::Debug, I2C> core::fmt::Debug
+    for DetectError<CommE, I2C>
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("DetectError")
+            .field("failure", &self.failure)
+            .finish_non_exhaustive()
     }
+}
 
-    /// Read a single register
-    fn read_reg(&mut self, reg: u8) -> Result<u8, crate::Error<CommE, PinE>> {
-        self.sensor_interface
-            .read_block(reg, &mut self.block_buf[..1])?;
-        Ok(self.block_buf[0])
+/// Register map
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u8)]
+pub enum Register {
+    /// Configuration Register A
+    ConfigA = 0x00,
+    /// Configuration Register B
+    ConfigB = 0x01,
+    /// Mode Register; MD1:MD0 self-clear after a single measurement
+    ConfigC = 0x02,
+    /// X-axis output, MSB first
+    DataX = 0x03,
+    /// Z-axis output, MSB first (the chip orders the outputs X, Z, Y)
+    DataZ = 0x05,
+    /// Y-axis output, MSB first
+    DataY = 0x07,
+    /// Status Register
+    Status = 0x09,
+    /// Identification Register A
+    IdA = 0x0A,
+    /// Identification Register B
+    IdB = 0x0B,
+    /// Identification Register C
+    IdC = 0x0C,
+    /// Temperature output MSB, HMC5983 only
+    TempMsb = 0x31,
+    /// Temperature output LSB, HMC5983 only
+    TempLsb = 0x32,
+}
+
+impl Register {
+    /// The register address on the bus
+    pub fn to_addr(self) -> u8 {
+        self as u8
     }
+}
 
-    /// Verify that a magnetometer reading is within the expected range.
-    // fn reading_in_range(sample: &[i16; 3]) -> bool {
-    //     /// Maximum Dynamic Range for X and Y axes (micro Teslas)
-    //     const MDR_XY_AXES: i16 = 1600;
-    //     /// Maximum Dynamic Range for Z axis (micro Teslas)
-    //     const MDR_Z_AXIS: i16 = 2500;
-    //     /// Resolution (micro Teslas per LSB)
-    //     const RESO_PER_BIT: f32 = 0.3;
-    //     const MAX_VAL_XY: i16 =
-    //         (((MDR_XY_AXES as f32) / RESO_PER_BIT) as i16) + 1;
-    //     const MAX_VAL_Z: i16 =
-    //         (((MDR_Z_AXIS as f32) / RESO_PER_BIT) as i16) + 1;
-    //
-    //     sample[0].abs() < MAX_VAL_XY
-    //         && sample[1].abs() < MAX_VAL_XY
-    //         && sample[2].abs() < MAX_VAL_Z
-    // }
+/// Bits of Config C that keep their written value after writing `mode`.
+/// In single-measurement mode the MD1:MD0 bits self-clear to idle once the
+/// measurement completes, so they are excluded; in other modes every bit
+/// is stable. Config A and Config B have no self-clearing bits.
+pub fn config_c_stable_mask(mode: OperatingMode) -> u8 {
+    const MODE_BITS: u8 = 0b11;
+    match mode {
+        OperatingMode::Single => !MODE_BITS,
+        _ => 0xFF,
+    }
+}
+
+/// Combine two's-complement bytes sent MSB first, as the chip's data
+/// and temperature outputs are: `combine_be(0xFF, 0x38) == -200`
+pub fn combine_be(msb: u8, lsb: u8) -> i16 {
+    i16::from_be_bytes([msb, lsb])
+}
+
+/// How the mag data bytes encode signed values
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DataEncoding {
+    /// Two's complement, as the HMC5983 datasheet specifies (the default)
+    TwosComplement,
+    /// Sign bit plus 15-bit magnitude. A workaround for clone chips that
+    /// reportedly encode their data this way; genuine parts never need it.
+    SignMagnitude,
+}
 
-    /// Combine high and low bytes of i16 mag value
-    fn raw_reading_to_i16(buf: &[u8], idx: usize) -> i16 {
-        let val: i16 = ((buf[idx] as i16) << 8) | (buf[idx + 1] as i16);
-        val
+impl DataEncoding {
+    /// Decode bytes sent MSB first
+    pub const fn decode(self, msb: u8, lsb: u8) -> i16 {
+        match self {
+            DataEncoding::TwosComplement => i16::from_be_bytes([msb, lsb]),
+            DataEncoding::SignMagnitude => {
+                let magnitude = i16::from_be_bytes([msb & 0x7F, lsb]);
+                if msb & 0x80 != 0 {
+                    -magnitude
+                } else {
+                    magnitude
+                }
+            }
+        }
     }
+}
 
-    pub fn get_mag_vector(
-        &mut self,
-    ) -> Result<[i16; 3], crate::Error<CommE, PinE>> {
-        const XYZ_DATA_LEN: usize = 6;
+/// Combine two's-complement bytes sent LSB first:
+/// `combine_le(0x38, 0xFF) == -200`
+pub fn combine_le(lsb: u8, msb: u8) -> i16 {
+    i16::from_le_bytes([lsb, msb])
+}
 
-        //get the actual mag data from the sensor
-        self.sensor_interface.read_block(
-            REG_MAG_DATA_START,
-            &mut self.block_buf[..XYZ_DATA_LEN],
-        )?;
-        let sample_i16 = [
-            Self::raw_reading_to_i16(&self.block_buf, 0),
-            Self::raw_reading_to_i16(&self.block_buf, 2),
-            Self::raw_reading_to_i16(&self.block_buf, 4),
-        ];
+/// Operating mode settings (Config C / Mode register)
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u8)]
+pub enum OperatingMode {
+    /// Continuous-measurement mode
+    Continuous = 0b00,
+    /// Single-measurement mode: one measurement, then return to idle
+    Single = 0b01,
+    /// Idle mode
+    Idle = 0b10,
+}
 
-        // if !Self::reading_in_range(&sample_i16) {
-        //     #[cfg(feature = "rttdebug")]
-        //     rprintln!("bad reading?");
-        //
-        //     return Err(Error::OutOfRange);
-        // }
+/// Complete set of sensor configuration choices
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Config {
+    pub mode: MeasurementModeSetting,
+    pub odr: OdrSetting,
+    pub averaging: SampleAvgSetting,
+    pub temp_enabled: bool,
+    pub gain: GainSetting,
+    pub operating_mode: OperatingMode,
+}
 
-        //TODO do cross-axis flow calibration?
-        Ok(sample_i16)
+impl Config {
+    /// Low-rate preset for loggers sampling about once per second:
+    /// single-measurement mode, 0.75 Hz ODR, no averaging, temperature off.
+    /// The chip idles between measurements, so average current is
+    /// roughly 15 µA at one reading per second (datasheet: 2 µA idle,
+    /// 100 µA average at 7.5 Hz continuous).
+    pub fn low_power() -> Self {
+        Self {
+            mode: MeasurementModeSetting::NormalMode,
+            odr: OdrSetting::Odr0_75Hz,
+            averaging: SampleAvgSetting::AvgSamples1,
+            temp_enabled: false,
+            gain: GainSetting::Gain1090,
+            operating_mode: OperatingMode::Single,
+        }
     }
 
-    /// Read temperature from device
-    /// Result is degrees Celsius
-    pub fn get_temperature(
-        &mut self,
-    ) -> Result<i16, crate::Error<CommE, PinE>> {
-        const TEMP_DATA_LEN: usize = 2;
+    /// High-rate preset: continuous mode, 220 Hz ODR, no averaging.
+    /// Each sample must be read within its 4.5 ms period: pair this with
+    /// `read_mag_blocking_until_ready` (DRDY or Status) in the read loop.
+    /// Polling Status and reading the data takes about 123 I2C bit-times,
+    /// so the bus must run at 27 kHz or faster before software overhead;
+    /// standard-mode 100 kHz I2C or any practical SPI clock is sufficient.
+    pub fn high_rate() -> Self {
+        Self {
+            mode: MeasurementModeSetting::NormalMode,
+            odr: OdrSetting::Odr220_0Hz,
+            averaging: SampleAvgSetting::AvgSamples1,
+            temp_enabled: false,
+            gain: GainSetting::Gain1090,
+            operating_mode: OperatingMode::Continuous,
+        }
+    }
 
-        self.sensor_interface.read_block(
-            REG_TEMP_OUTPUT_MSB,
-            &mut self.block_buf[..TEMP_DATA_LEN],
-        )?;
+    /// The setup used by the PX4 and ArduPilot HMC5883/HMC5983 drivers, for
+    /// porting flight firmware: continuous mode, 75 Hz ODR, normal (unbiased)
+    /// measurement, ±1.9 Ga range (gain 820), one sample per output, and
+    /// temperature compensation off (PX4 leaves it off unless configured).
+    pub fn px4_default() -> Self {
+        Self {
+            mode: MeasurementModeSetting::NormalMode,
+            odr: OdrSetting::Odr75_0Hz,
+            averaging: SampleAvgSetting::AvgSamples1,
+            temp_enabled: false,
+            gain: GainSetting::Gain0820,
+            operating_mode: OperatingMode::Continuous,
+        }
+    }
+}
 
-        //TODO datasheet is not clear whether the temp can go negative
-        // Temperature=(MSB*2^8+LSB)/(2^4*8)+25in C
-        let celsius = (((self.block_buf[0] as i16) * 256)
-            + (self.block_buf[1] as i16))
-            / 128
-            + 25;
-        Ok(celsius)
+impl Default for Config {
+    /// Power-on defaults of the chip
+    fn default() -> Self {
+        Self {
+            mode: MeasurementModeSetting::NormalMode,
+            odr: OdrSetting::Odr15_0Hz,
+            averaging: SampleAvgSetting::AvgSamples1,
+            temp_enabled: false,
+            gain: GainSetting::Gain1090,
+            operating_mode: OperatingMode::Single,
+        }
     }
 }
 
-const REG_CONFIG_A: u8 = 0x00;
-const REG_CONFIG_B: u8 = 0x01;
-const REG_CONFIG_C: u8 = 0x02;
+/// Source of a reported axis: which physical sensor axis, and its sign
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AxisSource {
+    PlusX,
+    MinusX,
+    PlusY,
+    MinusY,
+    PlusZ,
+    MinusZ,
+}
+
+impl AxisSource {
+    /// Index of the physical axis (0 = X, 1 = Y, 2 = Z)
+    fn axis_index(self) -> usize {
+        match self {
+            AxisSource::PlusX | AxisSource::MinusX => 0,
+            AxisSource::PlusY | AxisSource::MinusY => 1,
+            AxisSource::PlusZ | AxisSource::MinusZ => 2,
+        }
+    }
+
+    fn is_negative(self) -> bool {
+        matches!(
+            self,
+            AxisSource::MinusX | AxisSource::MinusY | AxisSource::MinusZ
+        )
+    }
+}
 
-/// X-axis output value register
-const REG_DATA_X: u8 = 0x03;
-// Y-axis output value register
-// const REG_DATA_Y:u8 = 0x05;
-// Z-axis output value register
-// const REG_DATA_Z:u8	= 0x07;
+/// Maps the physical sensor axes onto the reported X/Y/Z axes,
+/// allowing the board to be mounted in any orthogonal orientation.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AxisRemap {
+    /// Source of the reported X axis
+    pub x: AxisSource,
+    /// Source of the reported Y axis
+    pub y: AxisSource,
+    /// Source of the reported Z axis
+    pub z: AxisSource,
+}
 
-// const REG_STATUS:u8 = 0x09;
+impl AxisRemap {
+    /// Reported axes match the physical sensor axes
+    pub const IDENTITY: AxisRemap = AxisRemap {
+        x: AxisSource::PlusX,
+        y: AxisSource::PlusY,
+        z: AxisSource::PlusZ,
+    };
 
-/// Register to read out all three dimensions of mag data
-const REG_MAG_DATA_START: u8 = REG_DATA_X;
+    /// Each physical axis must be used exactly once
+    pub fn is_valid(&self) -> bool {
+        let (x, y, z) = (
+            self.x.axis_index(),
+            self.y.axis_index(),
+            self.z.axis_index(),
+        );
+        x != y && y != z && x != z
+    }
 
-/// Identification Register A
-const REG_ID_A: u8 = 0x0A;
-// Identification Register B
-// const REG_ID_B: u8 = 0x0B;
-// Identification Register C
-// const REG_ID_C: u8 = 0x0C;
+    /// Apply this remapping to a raw sample in the sensor frame
+    pub fn apply(&self, sample: [i16; 3]) -> [i16; 3] {
+        let pick = |src: AxisSource| {
+            let val = sample[src.axis_index()];
+            if src.is_negative() {
+                val.saturating_neg()
+            } else {
+                val
+            }
+        };
+        [pick(self.x), pick(self.y), pick(self.z)]
+    }
+}
 
-/// Temperature outputs, HMC5983
-const REG_TEMP_OUTPUT_MSB: u8 = 0x31;
-// const REG_TEMP_OUTPUT_LSB: u8 = 0x32;
+impl Default for AxisRemap {
+    fn default() -> Self {
+        Self::IDENTITY
+    }
+}
 
-// Status Register 2
-// const REG_STATUS2: u8 = 0x09;
+/// Convert a sample in the sensor body frame to North-East-Down.
+///
+/// The body frame is `CoordinateFrame::Sensor` for a chip mounted face up
+/// with its X arrow toward the front of the vehicle: +X forward, +Y left,
+/// +Z up. Use `set_axis_remap` to bring other mountings into this frame
+/// first. With the vehicle level and facing north, NED is then (X, -Y, -Z).
+pub fn to_ned(sample: [i16; 3]) -> [i16; 3] {
+    [
+        sample[0],
+        sample[1].saturating_neg(),
+        sample[2].saturating_neg(),
+    ]
+}
 
-const BLOCK_BUF_LEN: usize = 32;
+/// Convert a sample in the sensor body frame (see `to_ned`) to
+/// East-North-Up: with the vehicle level and facing north, ENU is (-Y, X, Z).
+pub fn to_enu(sample: [i16; 3]) -> [i16; 3] {
+    [sample[1].saturating_neg(), sample[0], sample[2]]
+}
+
+/// An in-progress single measurement, from `begin_single_measurement`.
+/// Holds the driver until dropped, then returns the chip to idle.
+pub struct SingleMeasurement<'a, SI: SensorInterface, D, const W: usize = 0> {
+    driver: &'a mut HMC5983<SI, W>,
+    delay_source: &'a mut D,
+}
+
+impl<SI, D, CommE, PinE, const W: usize> SingleMeasurement<'_, SI, D, W>
+where
+    SI: SensorInterface<InterfaceError = crate::Error<CommE, PinE>>,
+    D: DelayMs<u8>,
+{
+    /// Wait for the measurement to complete, then read it
+    pub fn read(&mut self) -> Result<[i16; 3], crate::Error<CommE, PinE>> {
+        let wait_ms = self.driver.single_measurement_time_ms();
+        HMC5983::<SI, W>::delay_ms_long(self.delay_source, wait_ms);
+        self.driver.read_triggered_result()
+    }
+}
+
+impl<SI: SensorInterface, D, const W: usize> Drop
+    for SingleMeasurement<'_, SI, D, W>
+{
+    fn drop(&mut self) {
+        // best effort: there is no way to report an error from drop
+        let idle = OperatingMode::Idle;
+        let _ = self
+            .driver
+            .sensor_interface
+            .write_reg(Register::ConfigC.to_addr(), idle as u8);
+        self.driver.config.operating_mode = idle;
+    }
+}
+
+/// `W` is the length of the driver's sample window (see `latest_n`),
+/// zero unless constructed with `new_with_window`.
+pub struct HMC5983<SI, const W: usize = 0> {
+    pub(crate) sensor_interface: SI,
+    /// Buffer for reads and writes to the sensor
+    block_buf: [u8; BLOCK_BUF_LEN],
+    /// Mapping from sensor axes to reported axes
+    axis_remap: AxisRemap,
+    /// Last configuration written to the sensor
+    config: Config,
+    /// Chip variant declared by the user
+    variant: Variant,
+    /// Consecutive small readings seen by `get_mag_vector_autorange`
+    autorange_small_count: u8,
+    /// Software calibration applied by `get_mag_vector_calibrated`
+    calibration: Calibration,
+    /// Rotation from sensor frame to body frame, applied after calibration
+    mounting_rotation: [[f32; 3]; 3],
+    /// Treat an all-zero register snapshot as a disconnected sensor
+    disconnect_check: bool,
+    /// How status-polled reads handle locked output registers
+    lock_strategy: LockStrategy,
+    /// How `get_mag_vector` handles saturated readings
+    range_policy: RangePolicy,
+    /// Body axis treated as "forward" for heading
+    heading_reference: AxisSource,
+    /// Number of times a failed ID check is retried
+    id_check_retries: u8,
+    /// Accepts or rejects the ID register bytes
+    id_matcher: fn(&[u8; 3]) -> bool,
+    /// Number of times a data read failing with a bus error is retried
+    read_retries: u8,
+    /// Wait between a register write and its verifying readback
+    write_settle_us: u16,
+    /// Magnetic declination added to headings, degrees east of true north
+    declination_deg: f32,
+    /// Periodic self-check state for `read_mag_blocking_until_ready`
+    self_check: PeriodicSelfCheck,
+    /// Raw temperature conversion
+    temperature_scale: TemperatureScale,
+    /// Encoding of the mag data bytes
+    data_encoding: DataEncoding,
+    /// Sensor axes that saturated in the most recent sample
+    last_saturated: [bool; 3],
+    /// Result of the most recent `self_test`
+    last_self_test: Option<SelfTestResult>,
+    /// Weight of each new sample in the calibrated-read EMA filter
+    ema_alpha: f32,
+    /// Filtered output of the last calibrated read, if any
+    ema_state: Option<[f32; 3]>,
+    /// The latest `W` samples read by `get_mag_vector`
+    window: SampleWindow<W>,
+}
+
+/// State for self-tests interleaved with normal reads
+#[derive(Clone, Copy, Debug, Default)]
+struct PeriodicSelfCheck {
+    /// Normal reads between self-tests; zero disables the check
+    interval: usize,
+    /// Allowed fractional drift from the baseline response
+    tolerance: f32,
+    /// Normal reads since the last self-test
+    reads_since: usize,
+    /// Self-test response recorded by the first periodic check
+    baseline: Option<[i16; 3]>,
+}
+
+/// Adapts a microsecond delay source for methods that take `DelayMs<u8>`
+struct DelayUsAsMs<'a, D>(&'a mut D);
+
+impl<D: DelayUs<u16>> DelayMs<u8> for DelayUsAsMs<'_, D> {
+    fn delay_ms(&mut self, ms: u8) {
+        for _ in 0..ms {
+            self.0.delay_us(1000);
+        }
+    }
+}
+
+/// Skips the write settle time, for verified writes made without a
+/// delay source
+struct NoSettle;
+
+impl DelayUs<u16> for NoSettle {
+    fn delay_us(&mut self, _us: u16) {}
+}
+
+impl<I2C, CommE> HMC5983<I2cInterface<I2C>>
+where
+    I2C: hal::blocking::i2c::Write<Error = CommE>
+        + hal::blocking::i2c::Read<Error = CommE>
+        + hal::blocking::i2c::WriteRead<Error = CommE>,
+    CommE: core::fmt::Debug,
+{
+    /// Look for the sensor on an I2C bus and return a driver configured
+    /// as by `init`, for firmware that runs on boards with and without it.
+    /// Only `interface::i2c::I2C_ADDRESS` (0x1E) is probed, since the
+    /// chip's address is fixed. On failure the `DetectError` reports what
+    /// was found there, and gives the bus back.
+    pub fn auto_detect(
+        i2c: I2C,
+        delay_source: &mut impl DelayMs<u8>,
+    ) -> Result<Self, DetectError<CommE, I2C>> {
+        let mut driver = Self::new_with_interface(I2cInterface::new(i2c));
+        let failure = match driver.probe(delay_source) {
+            Ok(()) => match driver.configure_defaults(delay_source) {
+                Ok(()) => return Ok(driver),
+                Err(e) => DetectFailure::InitFailed(e),
+            },
+            Err(Error::UnknownChipId) => {
+                match driver.read_block(Register::IdA, ID_LEN) {
+                    Ok(()) => {
+                        let buf = &driver.block_buf;
+                        DetectFailure::UnknownDevice([buf[0], buf[1], buf[2]])
+                    }
+                    Err(e) => Self::no_response(e),
+                }
+            }
+            Err(e) => Self::no_response(e),
+        };
+        Err(DetectError {
+            failure,
+            i2c: driver.release().release(),
+        })
+    }
+
+    /// Classify an error from probing: a bus error means nothing answered
+    fn no_response(e: Error<CommE, ()>) -> DetectFailure<CommE> {
+        match e {
+            Error::Comm(error) | Error::CommAt { error, .. } => {
+                DetectFailure::NoResponse(error)
+            }
+            e => DetectFailure::InitFailed(e),
+        }
+    }
+}
+
+impl<SI, CommE, PinE> HMC5983<SI>
+where
+    SI: SensorInterface<InterfaceError = crate::Error<CommE, PinE>>,
+{
+    pub fn new_with_interface(sensor_interface: SI) -> Self {
+        Self::new_with_window(sensor_interface)
+    }
+
+    /// Construct the driver and immediately initialize it, failing
+    /// if no valid chip responds on the interface.
+    pub fn try_new(
+        sensor_interface: SI,
+        delay_source: &mut impl DelayMs<u8>,
+    ) -> Result<Self, crate::Error<CommE, PinE>> {
+        let mut driver = Self::new_with_interface(sensor_interface);
+        driver.init(delay_source)?;
+        Ok(driver)
+    }
+}
+
+impl<SI, CommE, PinE, const W: usize> HMC5983<SI, W>
+where
+    SI: SensorInterface<InterfaceError = crate::Error<CommE, PinE>>,
+{
+    /// Construct a driver that keeps the latest `W` samples read by
+    /// `get_mag_vector`, and the reads built on it, for moving-window
+    /// filtering (see `latest_n` and `moving_average`). Name the window
+    /// length with `HMC5983::<_, 8>::new_with_window(interface)`.
+    /// `new_with_interface` keeps no samples.
+    pub fn new_with_window(sensor_interface: SI) -> Self {
+        Self {
+            sensor_interface,
+            block_buf: [0; BLOCK_BUF_LEN],
+            axis_remap: AxisRemap::IDENTITY,
+            config: Config::default(),
+            variant: Variant::Hmc5983,
+            autorange_small_count: 0,
+            calibration: Calibration::IDENTITY,
+            mounting_rotation: IDENTITY_MATRIX,
+            disconnect_check: false,
+            lock_strategy: LockStrategy::ClearAndWait,
+            range_policy: RangePolicy::PassThrough,
+            heading_reference: AxisSource::PlusX,
+            id_check_retries: DEFAULT_ID_CHECK_RETRIES,
+            id_matcher: is_expected_id,
+            read_retries: 0,
+            write_settle_us: 0,
+            declination_deg: 0.0,
+            self_check: PeriodicSelfCheck::default(),
+            temperature_scale: HMC5983_TEMPERATURE_SCALE,
+            data_encoding: DataEncoding::TwosComplement,
+            last_saturated: [false; 3],
+            last_self_test: None,
+            ema_alpha: 1.0,
+            ema_state: None,
+            window: SampleWindow::new(),
+        }
+    }
+
+    /// Consume the driver and return the sensor interface, for example to
+    /// hand its bus to another driver. The chip keeps its configuration;
+    /// rebuild the driver with `new_with_interface` and `apply_config`.
+    pub fn release(self) -> SI {
+        self.sensor_interface
+    }
+
+    /// Verify that the chip is present, without changing its configuration.
+    /// Use `apply_config` later to program it; `init` does both.
+    pub fn probe(
+        &mut self,
+        delay_source: &mut impl DelayMs<u8>,
+    ) -> Result<(), crate::Error<CommE, PinE>> {
+        self.check_id_with_retries(delay_source)?;
+        Ok(())
+    }
+
+    /// Verify the chip is present and apply the default configuration
+    pub fn init(
+        &mut self,
+        delay_source: &mut impl DelayMs<u8>,
+    ) -> Result<(), crate::Error<CommE, PinE>> {
+        self.reset(delay_source)
+    }
+
+    /// Initialize the chip and return a bring-up report: the chip ID,
+    /// the self-test result, and the configuration registers read back
+    /// after initialization.
+    pub fn init_with_report(
+        &mut self,
+        delay_source: &mut impl DelayMs<u8>,
+    ) -> Result<InitReport, crate::Error<CommE, PinE>> {
+        let chip_id = self.check_id_with_retries(delay_source)?;
+        let self_test = self.self_test(delay_source)?;
+        self.configure_defaults(delay_source)?;
+        let config_a = self.read_reg(Register::ConfigA)?;
+        let config_b = self.read_reg(Register::ConfigB)?;
+        let config_c = self.read_reg(Register::ConfigC)?;
+        Ok(InitReport {
+            chip_id,
+            self_test,
+            config_a,
+            config_b,
+            config_c,
+        })
+    }
+
+    fn reset(
+        &mut self,
+        delay_source: &mut impl DelayMs<u8>,
+    ) -> Result<(), crate::Error<CommE, PinE>> {
+        self.check_id_with_retries(delay_source)?;
+        self.configure_defaults(delay_source)
+    }
+
+    /// Reinitialize the chip after a glitch such as a brownout: verify its
+    /// ID again and reprogram the configuration last applied (see `config`),
+    /// rather than the `init` defaults. Software state such as the
+    /// calibration, axis remap and heading settings is kept.
+    pub fn reinit(
+        &mut self,
+        delay_source: &mut impl DelayMs<u8>,
+    ) -> Result<(), crate::Error<CommE, PinE>> {
+        self.check_id_with_retries(delay_source)?;
+        self.configure_and_settle(self.config, delay_source)
+    }
+
+    /// Set how many times the ID check is retried during `init` and
+    /// `probe` before failing, for chips that wake slowly after power-up.
+    /// Attempts are `ID_CHECK_RETRY_DELAY_MS` apart.
+    /// Defaults to `DEFAULT_ID_CHECK_RETRIES`.
+    pub fn set_id_check_retries(&mut self, retries: u8) {
+        self.id_check_retries = retries;
+    }
+
+    /// Set how many times a mag data read that fails with a bus error is
+    /// retried before the last error is returned, so that a one-off glitch
+    /// (on a long cable, say) doesn't fail the read. Other register accesses
+    /// are not retried; `I2cInterface::max_retries` retries every
+    /// transaction instead. Defaults to zero.
+    pub fn set_read_retries(&mut self, retries: u8) {
+        self.read_retries = retries;
+    }
+
+    /// Set the test applied to the ID register bytes (A, B, C) by `init`
+    /// and `probe`, to accept known-good chips that report a non-standard
+    /// ID. Defaults to `is_expected_id`, a strict match on `EXPECTED_ID`.
+    pub fn set_id_matcher(&mut self, matcher: fn(&[u8; 3]) -> bool) {
+        self.id_matcher = matcher;
+    }
+
+    /// Wait `settle_us` after a register write before reading it back to
+    /// verify it (see `verify-writes`), in `set_gain_settled` and
+    /// `set_operating_mode_settled`. Use this if verified writes
+    /// (`set_gain`, for example) fail intermittently with
+    /// `Error::Configuration` although the register holds the right value
+    /// when read again later: on some hardware the readback otherwise races
+    /// the write commit. A few microseconds is usually enough.
+    /// Defaults to zero, which disables the wait.
+    pub fn set_write_settle(&mut self, settle_us: u16) {
+        self.write_settle_us = settle_us;
+    }
+
+    /// Wake the chip and verify its ID, retrying on failure
+    fn check_id_with_retries(
+        &mut self,
+        delay_source: &mut impl DelayMs<u8>,
+    ) -> Result<[u8; 3], crate::Error<CommE, PinE>> {
+        let mut attempts = 0;
+        loop {
+            match self.wakeup_and_check_id() {
+                Ok(id) => return Ok(id),
+                Err(e) => {
+                    if attempts >= self.id_check_retries {
+                        return Err(e);
+                    }
+                    attempts += 1;
+                    delay_source.delay_ms(ID_CHECK_RETRY_DELAY_MS);
+                }
+            }
+        }
+    }
+
+    /// Wake the chip and verify its ID, returning the ID bytes
+    fn wakeup_and_check_id(
+        &mut self,
+    ) -> Result<[u8; 3], crate::Error<CommE, PinE>> {
+        //wakeup the chip
+        for reg in Register::ConfigA.to_addr()..=Register::IdC.to_addr() {
+            let _val = self.read_reg_addr(reg)?;
+            #[cfg(feature = "rttdebug")]
+            rprintln!("0x{:0x} : {} ", reg, _val);
+        }
+
+        //read the product identifiers
+        self.read_block(Register::IdA, ID_LEN)?;
+        let id = [self.block_buf[0], self.block_buf[1], self.block_buf[2]];
+        //compare product ID against known product ID
+        if !(self.id_matcher)(&id) {
+            #[cfg(feature = "rttdebug")]
+            rprintln!("bad ID block: {},{},{}", id[0], id[1], id[2]);
+
+            if SI::IS_SPI && looks_like_wrong_spi_mode(&id) {
+                return Err(Error::LikelyWrongSpiMode);
+            }
+            return Err(Error::UnknownChipId);
+        }
+
+        Ok(id)
+    }
+
+    /// Apply the default continuous-mode configuration and wait for it
+    /// to settle. The HMC5883 has no temperature sensor, so Config A bit 7
+    /// stays clear on that variant.
+    fn configure_defaults(
+        &mut self,
+        delay_source: &mut impl DelayMs<u8>,
+    ) -> Result<(), crate::Error<CommE, PinE>> {
+        let config = Config {
+            temp_enabled: self.variant == Variant::Hmc5983,
+            ..INIT_CONFIG
+        };
+        self.configure_and_settle(config, delay_source)
+    }
+
+    /// Apply a configuration and wait for it to settle
+    fn configure_and_settle(
+        &mut self,
+        config: Config,
+        delay_source: &mut impl DelayMs<u8>,
+    ) -> Result<(), crate::Error<CommE, PinE>> {
+        self.apply_config(config)?;
+        self.discard_warmup_samples(delay_source)?;
+        let settling_ms = self.settling_time_ms();
+        Self::delay_ms_long(delay_source, settling_ms);
+
+        Ok(())
+    }
+
+    /// Run the positive-bias self-test: the chip applies a known field
+    /// to each axis, and each reading must fall within the datasheet limits.
+    /// The previous configuration is restored afterwards.
+    pub fn self_test(
+        &mut self,
+        delay_source: &mut impl DelayMs<u8>,
+    ) -> Result<SelfTestResult, crate::Error<CommE, PinE>> {
+        let saved = self.config;
+        let reading = self
+            .bias_reading(MeasurementModeSetting::PositiveBias, delay_source);
+        // restore the configuration even if the bias reading failed
+        self.apply_config(saved)?;
+        let result = Self::check_self_test(reading?, 1);
+        self.last_self_test = Some(result);
+        Ok(result)
+    }
+
+    /// Run the self-test with positive then negative bias (HMC5983 only;
+    /// the HMC5883 returns `Error::Unsupported`). Each response must fall
+    /// within the datasheet limits, and the two must be symmetric, per axis.
+    /// The previous configuration is restored afterwards.
+    pub fn self_test_full(
+        &mut self,
+        delay_source: &mut impl DelayMs<u8>,
+    ) -> Result<SelfTestReport, crate::Error<CommE, PinE>> {
+        if self.variant == Variant::Hmc5883 {
+            return Err(Error::Unsupported);
+        }
+        let saved = self.config;
+        let readings = self
+            .bias_reading(MeasurementModeSetting::PositiveBias, delay_source)
+            .and_then(|positive| {
+                self.bias_reading(
+                    MeasurementModeSetting::NegativeBias,
+                    delay_source,
+                )
+                .map(|negative| (positive, negative))
+            });
+        // restore the configuration even if a bias reading failed
+        self.apply_config(saved)?;
+        let (positive, negative) = readings?;
+
+        let mut symmetric = [false; 3];
+        for (i, sym) in symmetric.iter_mut().enumerate() {
+            let pos = positive[i] as i32;
+            let neg = -(negative[i] as i32);
+            let tolerance = pos.abs() * SELF_TEST_SYMMETRY_PERCENT / 100;
+            *sym = (pos - neg).abs() <= tolerance;
+        }
+        Ok(SelfTestReport {
+            positive: Self::check_self_test(positive, 1),
+            negative: Self::check_self_test(negative, -1),
+            symmetric,
+        })
+    }
+
+    /// Take a single measurement at the self-test gain with the given bias.
+    /// Leaves the self-test configuration applied.
+    fn bias_reading(
+        &mut self,
+        bias: MeasurementModeSetting,
+        delay_source: &mut impl DelayMs<u8>,
+    ) -> Result<[i16; 3], crate::Error<CommE, PinE>> {
+        self.set_all_config_a(
+            bias,
+            OdrSetting::Odr15_0Hz,
+            SampleAvgSetting::AvgSamples8,
+            false,
+        )?;
+        self.set_gain(SELF_TEST_GAIN)?;
+
+        // the first measurement after a gain change may use the old gain
+        let mut reading = [0; 3];
+        for _ in 0..2 {
+            self.set_operating_mode(OperatingMode::Single)?;
+            let wait_ms = self.single_measurement_time_ms();
+            Self::delay_ms_long(delay_source, wait_ms);
+            reading = self.read_raw_vector()?;
+        }
+        Ok(reading)
+    }
+
+    /// Check a self-test reading against the limits, negated for `sign` -1
+    fn check_self_test(reading: [i16; 3], sign: i16) -> SelfTestResult {
+        let mut passed = [false; 3];
+        for (pass, val) in passed.iter_mut().zip(reading.iter()) {
+            let response = val.saturating_mul(sign);
+            *pass = (SELF_TEST_LOW_LIMIT..=SELF_TEST_HIGH_LIMIT)
+                .contains(&response);
+        }
+        SelfTestResult { reading, passed }
+    }
+
+    /// Time in milliseconds from configuring the chip until the first
+    /// valid sample is available: one output period at the configured ODR,
+    /// plus one conversion for each averaged sample.
+    pub fn settling_time_ms(&self) -> u32 {
+        let period_ms = self.config.odr.period_us().div_ceil(1000);
+        period_ms + self.single_measurement_time_ms()
+    }
+
+    /// Time between samples at the configured ODR, in microseconds,
+    /// for aligning a read loop with the sensor's output cadence
+    pub fn measurement_period_us(&self) -> u32 {
+        self.config.odr.period_us()
+    }
+
+    /// How long to wait for the next sample before giving up:
+    /// two output periods
+    fn sample_timeout_ms(&self) -> u32 {
+        (2 * self.measurement_period_us()).div_ceil(1000)
+    }
+
+    /// Delay for longer than a single `DelayMs<u8>` call allows
+    fn delay_ms_long(delay_source: &mut impl DelayMs<u8>, ms: u32) {
+        let mut remaining = ms;
+        while remaining > 0 {
+            let chunk = remaining.min(u8::MAX as u32);
+            delay_source.delay_ms(chunk as u8);
+            remaining -= chunk;
+        }
+    }
+
+    /// Set the mag gain, which determines the range.
+    /// The write is verified by reading it back (see `verify-writes`);
+    /// if that fails intermittently, see `set_write_settle`.
+    /// `config` reports the new gain only once the write is confirmed.
+    pub fn set_gain(
+        &mut self,
+        gain: GainSetting,
+    ) -> Result<(), crate::Error<CommE, PinE>> {
+        self.set_gain_settled(gain, &mut NoSettle)
+    }
+
+    /// `set_gain`, waiting the write settle time (see `set_write_settle`)
+    /// before the verifying readback
+    pub fn set_gain_settled(
+        &mut self,
+        gain: GainSetting,
+        delay_source: &mut impl DelayUs<u16>,
+    ) -> Result<(), crate::Error<CommE, PinE>> {
+        let gain_val: u8 = gain as u8;
+        self.write_reg(Register::ConfigB, gain_val)?;
+        self.verify_reg(Register::ConfigB, gain_val, 0xFF, delay_source)?;
+        self.config.gain = gain;
+        Ok(())
+    }
+
+    /// Declare which chip variant is attached.
+    /// Both variants report the same ID bytes, so this cannot be detected.
+    /// Resets the temperature conversion to the variant's datasheet scale;
+    /// call `set_temperature_scale` afterwards to override it.
+    pub fn set_variant(&mut self, variant: Variant) {
+        self.variant = variant;
+        if let Some(scale) = variant.temperature_scale() {
+            self.temperature_scale = scale;
+        }
+    }
+
+    /// Override the temperature conversion, for compatible parts whose
+    /// datasheet specifies a different scale or offset.
+    /// Defaults to the variant's scale (see `Variant::temperature_scale`);
+    /// a scale that is not positive is rejected with `Error::Configuration`.
+    pub fn set_temperature_scale(
+        &mut self,
+        scale: TemperatureScale,
+    ) -> Result<(), crate::Error<CommE, PinE>> {
+        if scale.lsb_per_degree <= 0 {
+            return Err(Error::Configuration);
+        }
+        self.temperature_scale = scale;
+        Ok(())
+    }
+
+    /// The declared chip variant
+    pub fn variant(&self) -> Variant {
+        self.variant
+    }
+
+    /// The conversion used by `get_temperature`
+    pub fn temperature_scale(&self) -> TemperatureScale {
+        self.temperature_scale
+    }
+
+    /// Whether the chip can compensate its own sensitivity for temperature.
+    /// The HMC5983 does so automatically while its temperature sensor is
+    /// enabled (Config A bit 7, `Config::temp_enabled`); the HMC5883 can't.
+    /// Readings from a compensating chip are already corrected, so don't
+    /// apply a temperature correction to them in software as well.
+    pub fn has_builtin_temp_comp(&self) -> bool {
+        self.variant == Variant::Hmc5983
+    }
+
+    /// Whether built-in temperature compensation is currently engaged:
+    /// the chip supports it and the temperature sensor is enabled
+    pub fn temp_comp_active(&self) -> bool {
+        self.has_builtin_temp_comp() && self.config.temp_enabled
+    }
+
+    /// Enable or disable the disconnected-sensor check (off by default).
+    /// When enabled, an all-zero mag vector triggers a read of the config
+    /// and status registers; if those are also all zero, `get_mag_vector`
+    /// returns `Error::LikelyDisconnected`. A genuinely nulled field would
+    /// still leave the config registers set, but this is opt-in to be safe.
+    pub fn set_disconnect_check(&mut self, enabled: bool) {
+        self.disconnect_check = enabled;
+    }
+
+    /// Set how the physical sensor axes map to the reported axes.
+    /// The remapping is applied to every vector returned by `get_mag_vector`.
+    pub fn set_axis_remap(
+        &mut self,
+        remap: AxisRemap,
+    ) -> Result<(), crate::Error<CommE, PinE>> {
+        if !remap.is_valid() {
+            return Err(Error::Configuration);
+        }
+        self.axis_remap = remap;
+        Ok(())
+    }
+
+    /// Set all of the Config A register settings.
+    /// Negative bias and temperature-only modes, and the 220 Hz output rate
+    /// (reserved on that chip), return `Error::Unsupported` on the HMC5883
+    /// variant.
+    pub fn set_all_config_a(
+        &mut self,
+        mode: MeasurementModeSetting,
+        odr: OdrSetting,
+        averaging: SampleAvgSetting,
+        temp_enabled: bool,
+    ) -> Result<(), crate::Error<CommE, PinE>> {
+        let unsupported = matches!(
+            mode,
+            MeasurementModeSetting::NegativeBias
+                | MeasurementModeSetting::TemperatureOnly
+        ) || odr == OdrSetting::Odr220_0Hz;
+        if unsupported && self.variant == Variant::Hmc5883 {
+            return Err(Error::Unsupported);
+        }
+
+        let new_val = encode_config_a(mode, odr, averaging, temp_enabled);
+        self.write_reg(Register::ConfigA, new_val)?;
+        self.config.mode = mode;
+        self.config.odr = odr;
+        self.config.averaging = averaging;
+        self.config.temp_enabled = temp_enabled;
+        Ok(())
+    }
+
+    /// Set how many samples the chip averages per output: 1, 2, 4 or 8.
+    /// Other counts are rejected with `Error::Configuration`.
+    /// Other Config A fields keep their current settings.
+    pub fn set_averaging_count(
+        &mut self,
+        count: u8,
+    ) -> Result<(), crate::Error<CommE, PinE>> {
+        let averaging =
+            SampleAvgSetting::from_count(count).ok_or(Error::Configuration)?;
+        self.set_all_config_a(
+            self.config.mode,
+            self.config.odr,
+            averaging,
+            self.config.temp_enabled,
+        )
+    }
+
+    /// Set the measurement (bias) mode, held in Config A bits 1:0.
+    /// Other Config A fields keep their current settings.
+    pub fn set_bias(
+        &mut self,
+        bias: MeasurementModeSetting,
+    ) -> Result<(), crate::Error<CommE, PinE>> {
+        self.set_all_config_a(
+            bias,
+            self.config.odr,
+            self.config.averaging,
+            self.config.temp_enabled,
+        )
+    }
+
+    /// Set the operating mode (continuous, single, or idle),
+    /// held in Config C (the Mode register) bits 1:0.
+    /// The write is verified by reading back only the bits that are stable
+    /// for the chosen mode (see `config_c_stable_mask` and `verify-writes`).
+    pub fn set_operating_mode(
+        &mut self,
+        mode: OperatingMode,
+    ) -> Result<(), crate::Error<CommE, PinE>> {
+        self.set_operating_mode_settled(mode, &mut NoSettle)
+    }
+
+    /// `set_operating_mode`, waiting the write settle time
+    /// (see `set_write_settle`) before the verifying readback
+    pub fn set_operating_mode_settled(
+        &mut self,
+        mode: OperatingMode,
+        delay_source: &mut impl DelayUs<u16>,
+    ) -> Result<(), crate::Error<CommE, PinE>> {
+        let mode_val = mode as u8;
+        self.write_reg(Register::ConfigC, mode_val)?;
+        self.verify_reg(
+            Register::ConfigC,
+            mode_val,
+            config_c_stable_mask(mode),
+            delay_source,
+        )?;
+        self.config.operating_mode = mode;
+        Ok(())
+    }
+
+    /// Read back a register after a write and compare the bits in `mask`,
+    /// failing with `Error::Configuration` on a mismatch. Waits the write
+    /// settle time on `delay_source` before the readback.
+    /// Skipped when the `verify-writes` feature is disabled, saving a
+    /// transaction per write at the cost of not detecting failed writes.
+    fn verify_reg(
+        &mut self,
+        reg: Register,
+        expected: u8,
+        mask: u8,
+        delay_source: &mut impl DelayUs<u16>,
+    ) -> Result<(), crate::Error<CommE, PinE>> {
+        #[cfg(feature = "verify-writes")]
+        {
+            // the write must land before the readback, or it reads stale
+            self.sensor_interface.flush()?;
+            if self.write_settle_us > 0 {
+                delay_source.delay_us(self.write_settle_us);
+            }
+            let confirm_val = self.read_reg(reg)?;
+            if (confirm_val & mask) != (expected & mask) {
+                #[cfg(feature = "rttdebug")]
+                rprintln!(
+                    "{:?} bad: expected {} got {}",
+                    reg,
+                    expected,
+                    confirm_val
+                );
+                return Err(Error::Configuration);
+            }
+        }
+        #[cfg(not(feature = "verify-writes"))]
+        let _ = (reg, expected, mask, delay_source);
+        Ok(())
+    }
+
+    /// The configuration last written to the sensor
+    pub fn config(&self) -> Config {
+        self.config
+    }
+
+    /// Write a complete configuration to the sensor
+    pub fn apply_config(
+        &mut self,
+        config: Config,
+    ) -> Result<(), crate::Error<CommE, PinE>> {
+        self.set_all_config_a(
+            config.mode,
+            config.odr,
+            config.averaging,
+            config.temp_enabled,
+        )?;
+        self.set_gain(config.gain)?;
+        self.set_operating_mode(config.operating_mode)
+    }
+
+    /// Change gain, ODR and averaging without a glitch window: the chip is
+    /// idled while both registers are written, the prior operating mode is
+    /// restored, and the first sample at the new settings is discarded.
+    pub fn reconfigure(
+        &mut self,
+        gain: GainSetting,
+        odr: OdrSetting,
+        averaging: SampleAvgSetting,
+        delay_source: &mut impl DelayMs<u8>,
+    ) -> Result<(), crate::Error<CommE, PinE>> {
+        let prior_mode = self.config.operating_mode;
+        self.set_operating_mode(OperatingMode::Idle)?;
+        self.set_all_config_a(
+            self.config.mode,
+            odr,
+            averaging,
+            self.config.temp_enabled,
+        )?;
+        self.set_gain(gain)?;
+        self.set_operating_mode(prior_mode)?;
+        if prior_mode != OperatingMode::Idle {
+            Self::delay_ms_long(delay_source, self.settling_time_ms());
+            self.read_raw_vector()?;
+        }
+        Ok(())
+    }
+
+    /// Apply the `Config::low_power` preset and wait for the first
+    /// single measurement to complete
+    pub fn configure_low_power(
+        &mut self,
+        delay_source: &mut impl DelayMs<u8>,
+    ) -> Result<(), crate::Error<CommE, PinE>> {
+        self.apply_config(Config::low_power())?;
+        delay_source.delay_ms(SINGLE_MEASUREMENT_DELAY_MS);
+        Ok(())
+    }
+
+    /// Apply the `Config::high_rate` preset and discard warm-up samples
+    pub fn configure_high_rate(
+        &mut self,
+        delay_source: &mut impl DelayMs<u8>,
+    ) -> Result<(), crate::Error<CommE, PinE>> {
+        self.apply_config(Config::high_rate())?;
+        self.discard_warmup_samples(delay_source)
+    }
+
+    /// Enter continuous-measurement mode and discard the warm-up samples,
+    /// so that the next `get_mag_vector` reflects the configured gain
+    pub fn start_continuous(
+        &mut self,
+        delay_source: &mut impl DelayMs<u8>,
+    ) -> Result<(), crate::Error<CommE, PinE>> {
+        self.set_operating_mode(OperatingMode::Continuous)?;
+        self.discard_warmup_samples(delay_source)
+    }
+
+    /// Wait for and discard `CONTINUOUS_WARMUP_SAMPLES` samples
+    fn discard_warmup_samples(
+        &mut self,
+        delay_source: &mut impl DelayMs<u8>,
+    ) -> Result<(), crate::Error<CommE, PinE>> {
+        for _ in 0..CONTINUOUS_WARMUP_SAMPLES {
+            Self::delay_ms_long(delay_source, self.settling_time_ms());
+            self.read_raw_vector()?;
+        }
+        Ok(())
+    }
+
+    /// Read a single register
+    fn read_reg(
+        &mut self,
+        reg: Register,
+    ) -> Result<u8, crate::Error<CommE, PinE>> {
+        self.read_reg_addr(reg.to_addr())
+    }
+
+    /// Read a single register by raw address
+    fn read_reg_addr(
+        &mut self,
+        addr: u8,
+    ) -> Result<u8, crate::Error<CommE, PinE>> {
+        self.sensor_interface
+            .read_block(addr, &mut self.block_buf[..1])
+            .map_err(|e| e.at_register(addr))?;
+        Ok(self.block_buf[0])
+    }
+
+    /// Read `len` consecutive registers starting at `reg` into `block_buf`
+    fn read_block(
+        &mut self,
+        reg: Register,
+        len: usize,
+    ) -> Result<(), crate::Error<CommE, PinE>> {
+        if len == 0 || len > BLOCK_BUF_LEN {
+            return Err(Error::InvalidLength);
+        }
+        self.sensor_interface
+            .read_block(reg.to_addr(), &mut self.block_buf[..len])
+            .map_err(|e| e.at_register(reg.to_addr()))
+    }
+
+    /// Write a single register
+    fn write_reg(
+        &mut self,
+        reg: Register,
+        val: u8,
+    ) -> Result<(), crate::Error<CommE, PinE>> {
+        self.sensor_interface
+            .write_reg(reg.to_addr(), val)
+            .map_err(|e| e.at_register(reg.to_addr()))
+    }
+
+    // Verify that a magnetometer reading is within the expected range.
+    // fn reading_in_range(sample: &[i16; 3]) -> bool {
+    //     /// Maximum Dynamic Range for X and Y axes (micro Teslas)
+    //     const MDR_XY_AXES: i16 = 1600;
+    //     /// Maximum Dynamic Range for Z axis (micro Teslas)
+    //     const MDR_Z_AXIS: i16 = 2500;
+    //     /// Resolution (micro Teslas per LSB)
+    //     const RESO_PER_BIT: f32 = 0.3;
+    //     const MAX_VAL_XY: i16 =
+    //         (((MDR_XY_AXES as f32) / RESO_PER_BIT) as i16) + 1;
+    //     const MAX_VAL_Z: i16 =
+    //         (((MDR_Z_AXIS as f32) / RESO_PER_BIT) as i16) + 1;
+    //
+    //     sample[0].abs() < MAX_VAL_XY
+    //         && sample[1].abs() < MAX_VAL_XY
+    //         && sample[2].abs() < MAX_VAL_Z
+    // }
+
+    /// Combine high and low bytes of i16 mag value.
+    /// The chip sends each output MSB first (at the lower address).
+    fn raw_reading_to_i16(&self, buf: &[u8], idx: usize) -> i16 {
+        self.data_encoding.decode(buf[idx], buf[idx + 1])
+    }
+
+    /// Decode a block of mag data, which the chip orders X, Z, Y,
+    /// into X, Y, Z
+    fn decode_mag_data(&self, buf: &[u8]) -> [i16; 3] {
+        [
+            self.raw_reading_to_i16(buf, DATA_X_OFFSET),
+            self.raw_reading_to_i16(buf, DATA_Y_OFFSET),
+            self.raw_reading_to_i16(buf, DATA_Z_OFFSET),
+        ]
+    }
+
+    /// Set how the mag data bytes are decoded. Only for clone chips that
+    /// don't use two's complement; the default suits genuine parts.
+    pub fn set_data_encoding(&mut self, encoding: DataEncoding) {
+        self.data_encoding = encoding;
+    }
+
+    /// Read the mag vector, handling saturated axes according to the
+    /// range policy (see `set_range_policy`)
+    pub fn get_mag_vector(
+        &mut self,
+    ) -> Result<[i16; 3], crate::Error<CommE, PinE>> {
+        self.get_mag_vector_with_policy(self.range_policy)
+    }
+
+    /// `get_mag_vector` with the given range policy
+    fn get_mag_vector_with_policy(
+        &mut self,
+        policy: RangePolicy,
+    ) -> Result<[i16; 3], crate::Error<CommE, PinE>> {
+        let sample = self.read_mag_vector_with_policy(policy)?;
+        self.window.push(sample);
+        Ok(sample)
+    }
+
+    /// The latest samples read by `get_mag_vector`, oldest first: up to
+    /// `W` of them (see `new_with_window`)
+    pub fn latest_n(&self) -> &[[i16; 3]] {
+        self.window.latest_n()
+    }
+
+    /// Per-axis mean of `latest_n`, or zero if it is empty
+    pub fn moving_average(&self) -> [f32; 3] {
+        self.window.moving_average()
+    }
+
+    /// Empty the sample window, for example after changing the gain
+    pub fn clear_window(&mut self) {
+        self.window.clear();
+    }
+
+    /// Set how `get_mag_vector`, and the reads built on it, handle
+    /// saturated readings. Defaults to `RangePolicy::PassThrough`.
+    /// `get_mag_vector_with_flags` reports saturation instead, and
+    /// `get_mag_vector_autorange` applies the policy only once the coarsest
+    /// gain still saturates.
+    pub fn set_range_policy(&mut self, policy: RangePolicy) {
+        self.range_policy = policy;
+    }
+
+    fn read_mag_vector_with_policy(
+        &mut self,
+        policy: RangePolicy,
+    ) -> Result<[i16; 3], crate::Error<CommE, PinE>> {
+        let sample_i16 = self.read_raw_vector()?;
+        self.note_saturation(&sample_i16);
+
+        if self.disconnect_check && sample_i16 == [0; 3] {
+            let regs = self.read_config_data_status_raw()?;
+            if regs.iter().all(|r| *r == 0) {
+                return Err(Error::LikelyDisconnected);
+            }
+        }
+
+        // if !Self::reading_in_range(&sample_i16) {
+        //     #[cfg(feature = "rttdebug")]
+        //     rprintln!("bad reading?");
+        //
+        //     return Err(Error::OutOfRange);
+        // }
+
+        //TODO do cross-axis flow calibration?
+        Self::apply_range_policy(policy, self.axis_remap.apply(sample_i16))
+    }
+
+    fn apply_range_policy(
+        policy: RangePolicy,
+        mut sample: [i16; 3],
+    ) -> Result<[i16; 3], crate::Error<CommE, PinE>> {
+        match policy {
+            RangePolicy::Error if Self::is_saturated(&sample) => {
+                return Err(Error::OutOfRange);
+            }
+            RangePolicy::Clamp => {
+                for val in sample.iter_mut() {
+                    *val = (*val).clamp(-FULL_SCALE - 1, FULL_SCALE);
+                }
+            }
+            _ => {}
+        }
+        Ok(sample)
+    }
+
+    /// Read the mag vector in the sensor frame, without axis remapping
+    fn read_raw_vector(
+        &mut self,
+    ) -> Result<[i16; 3], crate::Error<CommE, PinE>> {
+        //get the actual mag data from the sensor
+        // Over I2C the register pointer rolls back from 0x08 to 0x03,
+        // so a 6-byte read from 0x03 always covers exactly the data registers.
+        // The chip orders the outputs X, Z, Y.
+        let mut attempts = 0;
+        loop {
+            match self.read_block(REG_MAG_DATA_START, MAG_DATA_LEN) {
+                Ok(()) => break,
+                Err(e @ Error::CommAt { .. }) | Err(e @ Error::Comm(_)) => {
+                    if attempts >= self.read_retries {
+                        return Err(e);
+                    }
+                    attempts += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        let sample_i16 = self.decode_mag_data(&self.block_buf);
+        Ok(sample_i16)
+    }
+
+    /// Read the mag vector (remapped, like `get_mag_vector`) together with
+    /// the Status flags describing it.
+    /// Over SPI, data and Status (0x03..=0x09) are read in one transaction,
+    /// so Status can't change between the two. Over I2C the register pointer
+    /// doesn't advance past the data, so Status is read first in a separate
+    /// transaction; a sample arriving in between makes the data newer than
+    /// Status reports, never older.
+    pub fn read_data_and_status(
+        &mut self,
+    ) -> Result<([i16; 3], Status), crate::Error<CommE, PinE>> {
+        let (sample, status) = if SI::STATUS_FOLLOWS_DATA {
+            self.read_block(REG_MAG_DATA_START, DATA_STATUS_LEN)?;
+            let sample = self.decode_mag_data(&self.block_buf);
+            (sample, self.block_buf[MAG_DATA_LEN])
+        } else {
+            let status = self.read_reg(Register::Status)?;
+            (self.read_raw_vector()?, status)
+        };
+        self.note_saturation(&sample);
+        Ok((self.axis_remap.apply(sample), Status::decode_status(status)))
+    }
+
+    /// Record which sensor axes of a sample saturated, for `read_health`
+    fn note_saturation(&mut self, sample: &[i16; 3]) {
+        for (sat, val) in self.last_saturated.iter_mut().zip(sample.iter()) {
+            *sat = val.saturating_abs() >= OVERFLOW_VALUE.saturating_abs();
+        }
+    }
+
+    /// One-call health check for a control loop: reads the Status register,
+    /// and reports it along with which sensor axes saturated in the most
+    /// recent sample and the most recent `self_test` result, if any.
+    /// Costs a single register read. The saturation flags are cached from
+    /// the last read of the mag data, so they are only as fresh as that
+    /// read; the data registers are left alone so that a pending sample
+    /// is not consumed.
+    pub fn read_health(&mut self) -> Result<Health, crate::Error<CommE, PinE>> {
+        let status = Status::decode_status(self.read_reg(Register::Status)?);
+        Ok(Health {
+            status,
+            saturated: self.last_saturated,
+            self_test: self.last_self_test,
+        })
+    }
+
+    /// Read the mag vector one data register at a time instead of in one
+    /// auto-increment block. Use this only on parts (such as some clones)
+    /// whose block reads return corrupt data: it takes six transactions
+    /// rather than one, so the output registers stay locked for longer and
+    /// the highest sustainable output rate drops.
+    /// Axis remapping and the range policy apply as for `get_mag_vector`.
+    pub fn get_mag_vector_single_regs(
+        &mut self,
+    ) -> Result<[i16; 3], crate::Error<CommE, PinE>> {
+        let mut data = [0u8; MAG_DATA_LEN];
+        for (offset, byte) in data.iter_mut().enumerate() {
+            *byte = self
+                .read_reg_addr(REG_MAG_DATA_START.to_addr() + offset as u8)?;
+        }
+        let sample = self.decode_mag_data(&data);
+        self.note_saturation(&sample);
+        Self::apply_range_policy(
+            self.range_policy,
+            self.axis_remap.apply(sample),
+        )
+    }
+
+    /// Read the mag vector along with saturation and data-ready flags.
+    /// Saturated axes are reported rather than failing the read.
+    pub fn get_mag_vector_with_flags(
+        &mut self,
+    ) -> Result<(MagReading, SampleFlags), crate::Error<CommE, PinE>> {
+        let data_ready = self.data_ready()?;
+        let sample =
+            self.read_mag_vector_with_policy(RangePolicy::PassThrough)?;
+        let mut flags = SampleFlags {
+            data_ready,
+            ..Default::default()
+        };
+        for (sat, val) in flags.saturated.iter_mut().zip(sample.iter()) {
+            *sat = val.saturating_abs() >= OVERFLOW_VALUE.saturating_abs();
+        }
+        Ok((sample, flags))
+    }
+
+    /// Check the Status register for a new sample
+    pub fn data_ready(&mut self) -> Result<bool, crate::Error<CommE, PinE>> {
+        let status = self.read_reg(Register::Status)?;
+        Ok((status & STATUS_RDY) != 0)
+    }
+
+    /// Check Status for a new sample, applying the lock strategy:
+    /// with `ClearAndWait`, a locked (stale) sample is released and
+    /// reported as not ready, so the caller waits for a fresh one.
+    fn poll_status_ready(&mut self) -> Result<bool, crate::Error<CommE, PinE>> {
+        let status = self.read_reg(Register::Status)?;
+        if (status & STATUS_LOCK) != 0
+            && self.lock_strategy == LockStrategy::ClearAndWait
+        {
+            self.read_raw_vector()?;
+            return Ok(false);
+        }
+        Ok((status & STATUS_RDY) != 0)
+    }
+
+    /// Release the output registers if they are locked by a partial read.
+    /// Reading all six data bytes releases the lock; the next measurement
+    /// then updates the registers. Returns whether the lock was set.
+    pub fn clear_output_lock(
+        &mut self,
+    ) -> Result<bool, crate::Error<CommE, PinE>> {
+        let status = self.read_reg(Register::Status)?;
+        let locked = (status & STATUS_LOCK) != 0;
+        if locked {
+            self.read_raw_vector()?;
+        }
+        Ok(locked)
+    }
+
+    /// Set how status-polled reads handle locked output registers
+    pub fn set_lock_strategy(&mut self, strategy: LockStrategy) {
+        self.lock_strategy = strategy;
+    }
+
+    /// Start a single measurement without waiting for it, so that many
+    /// sensors can be triggered nearly simultaneously. Wait at least
+    /// `single_measurement_time_ms`, then collect each sensor's result with
+    /// `read_triggered_result`. The chip returns to idle afterwards.
+    pub fn trigger_single_measurement(
+        &mut self,
+    ) -> Result<(), crate::Error<CommE, PinE>> {
+        self.set_operating_mode(OperatingMode::Single)
+    }
+
+    /// Time for a triggered single measurement to complete, in milliseconds:
+    /// one conversion per averaged sample
+    pub fn single_measurement_time_ms(&self) -> u32 {
+        (self.config.averaging.count() as u32)
+            * (SINGLE_MEASUREMENT_DELAY_MS as u32)
+    }
+
+    /// Start a single measurement and return a guard that reads its result.
+    /// Dropping the guard returns the chip to idle, even if the result was
+    /// never read (after an early return on an error, say), so a
+    /// single-shot sequence can't leave the chip in an unexpected mode.
+    pub fn begin_single_measurement<'a, D: DelayMs<u8>>(
+        &'a mut self,
+        delay_source: &'a mut D,
+    ) -> Result<SingleMeasurement<'a, SI, D, W>, crate::Error<CommE, PinE>>
+    {
+        self.trigger_single_measurement()?;
+        Ok(SingleMeasurement {
+            driver: self,
+            delay_source,
+        })
+    }
+
+    /// Read the result of `trigger_single_measurement`.
+    /// Returns `Error::Timeout` if the measurement has not completed.
+    pub fn read_triggered_result(
+        &mut self,
+    ) -> Result<[i16; 3], crate::Error<CommE, PinE>> {
+        self.try_get_mag_vector()?.ok_or(Error::Timeout)
+    }
+
+    /// Read the mag vector only if a new sample is ready, without blocking.
+    /// Returns `Ok(None)` if the Status register shows no new sample.
+    pub fn try_get_mag_vector(
+        &mut self,
+    ) -> Result<Option<[i16; 3]>, crate::Error<CommE, PinE>> {
+        if !self.data_ready()? {
+            return Ok(None);
+        }
+        self.get_mag_vector().map(Some)
+    }
+
+    /// Interleave a positive-bias self-test every `interval` reads made with
+    /// `read_mag_blocking_until_ready`, to catch sensor degradation in the
+    /// field. Multi-sample reads such as `capture_burst` are not
+    /// interrupted, and do not count towards the interval. The first check
+    /// records a baseline response; later checks fail with
+    /// `Error::SelfTestFailed` if any axis is outside the datasheet limits
+    /// or drifts from the baseline by more than `tolerance` (a fraction,
+    /// e.g. 0.1 for 10%). An `interval` of zero disables this.
+    pub fn enable_periodic_self_check(
+        &mut self,
+        interval: usize,
+        tolerance: f32,
+    ) {
+        self.self_check = PeriodicSelfCheck {
+            interval,
+            tolerance: tolerance.abs(),
+            ..Default::default()
+        };
+    }
+
+    /// Run the periodic self-check if one is due
+    fn periodic_self_check(
+        &mut self,
+        delay_source: &mut impl DelayUs<u16>,
+    ) -> Result<(), crate::Error<CommE, PinE>> {
+        let check = self.self_check;
+        if check.interval == 0 || check.reads_since < check.interval {
+            self.self_check.reads_since += 1;
+            return Ok(());
+        }
+        self.self_check.reads_since = 0;
+
+        let mut delay_ms = DelayUsAsMs(delay_source);
+        let result = self.self_test(&mut delay_ms)?;
+        // the first sample after restoring the gain is still a bias reading
+        self.discard_warmup_samples(&mut delay_ms)?;
+        if !result.all_passed() {
+            return Err(Error::SelfTestFailed);
+        }
+        match check.baseline {
+            None => self.self_check.baseline = Some(result.reading),
+            Some(baseline) => {
+                for (val, base) in result.reading.iter().zip(baseline.iter()) {
+                    let drift = ((*val as f32) - (*base as f32)).abs();
+                    if drift > (*base as f32).abs() * check.tolerance {
+                        return Err(Error::SelfTestFailed);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Wait until a new sample is ready, then read it.
+    /// If a DRDY pin is supplied it is polled for its active-low pulse;
+    /// if the pin is absent or cannot be read, the Status register is polled.
+    /// Returns `Error::Timeout` if no sample is ready within `timeout_ms`.
+    /// Before timing out, output registers left locked by an earlier
+    /// interrupted read are released, so the next read gets a fresh sample
+    /// rather than the stale one.
+    /// Runs the periodic self-check first when one is due.
+    pub fn read_mag_blocking_until_ready<P: InputPin>(
+        &mut self,
+        drdy: Option<&P>,
+        timeout_ms: u32,
+        delay_source: &mut impl DelayUs<u16>,
+    ) -> Result<[i16; 3], crate::Error<CommE, PinE>> {
+        self.periodic_self_check(delay_source)?;
+        self.wait_and_read(drdy, timeout_ms, self.range_policy, delay_source)
+    }
+
+    /// `read_mag_blocking_until_ready` without the periodic self-check,
+    /// for the reads within a multi-sample operation, reading the sample
+    /// with the given range policy
+    fn wait_and_read<P: InputPin>(
+        &mut self,
+        drdy: Option<&P>,
+        timeout_ms: u32,
+        policy: RangePolicy,
+        delay_source: &mut impl DelayUs<u16>,
+    ) -> Result<[i16; 3], crate::Error<CommE, PinE>> {
+        let timeout_us = timeout_ms.saturating_mul(1000);
+        let mut waited_us: u32 = 0;
+        let mut use_drdy = drdy.is_some();
+        loop {
+            let ready = match drdy {
+                Some(pin) if use_drdy => pin.is_low().unwrap_or_else(|_| {
+                    use_drdy = false;
+                    false
+                }),
+                _ => self.poll_status_ready()?,
+            };
+            if ready {
+                return self.get_mag_vector_with_policy(policy);
+            }
+            if waited_us >= timeout_us {
+                // best effort: the timeout is the error to report
+                let _ = self.clear_output_lock();
+                return Err(Error::Timeout);
+            }
+            let interval_us = if use_drdy {
+                DRDY_POLL_INTERVAL_US
+            } else {
+                STATUS_POLL_INTERVAL_US
+            };
+            delay_source.delay_us(interval_us);
+            waited_us += interval_us as u32;
+        }
+    }
+
+    /// Read a new sample, if one is ready, and pass it to `f`. Returns
+    /// whether `f` was called. For interrupt-driven designs: call this from
+    /// the DRDY interrupt handler (or a timer task), and the driver manages
+    /// the read while the sample handling stays in `f`.
+    /// A sample counts as ready while the DRDY pin (if supplied) is low,
+    /// or otherwise when Status reports it, which also covers a DRDY pulse
+    /// that ended before the handler ran.
+    /// Not re-entrant: the caller must have exclusive access to the driver
+    /// (for example from a critical section or RTOS mutex), and `f` runs in
+    /// the caller's context, inside the interrupt if called from one, so it
+    /// should be brief.
+    pub fn on_data_ready<P: InputPin, F: FnMut([i16; 3])>(
+        &mut self,
+        drdy: Option<&P>,
+        mut f: F,
+    ) -> Result<bool, crate::Error<CommE, PinE>> {
+        let pin_ready = drdy.is_some_and(|pin| pin.is_low().unwrap_or(false));
+        if pin_ready || self.poll_status_ready()? {
+            f(self.get_mag_vector()?);
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    /// Read the mag vector, adjusting the gain to suit the field strength.
+    /// A saturated axis steps to a wider range and re-reads. When every axis
+    /// stays below 1/8 of full scale for `AUTORANGE_STEP_UP_SAMPLES`
+    /// consecutive reads, the gain steps to a finer resolution and the read
+    /// is repeated. The result is always at the gain `config` reports.
+    /// Adjacent gains differ by less than 2x, so a reading below 1/8 of full
+    /// scale cannot saturate at the finer gain: the two thresholds are far
+    /// enough apart that the gain does not oscillate.
+    pub fn get_mag_vector_autorange(
+        &mut self,
+        delay_source: &mut impl DelayMs<u8>,
+    ) -> Result<[i16; 3], crate::Error<CommE, PinE>> {
+        let mut sample =
+            self.read_mag_vector_with_policy(RangePolicy::PassThrough)?;
+        while Self::is_saturated(&sample) {
+            self.autorange_small_count = 0;
+            match self.config.gain.coarser() {
+                Some(gain) => {
+                    sample = self.change_gain_and_read(gain, delay_source)?;
+                }
+                // already at the coarsest gain
+                None => {
+                    return Self::apply_range_policy(self.range_policy, sample)
+                }
+            }
+        }
+
+        let small = sample.iter().all(|v| v.saturating_abs() < AUTORANGE_SMALL);
+        if !small {
+            self.autorange_small_count = 0;
+        } else if let Some(gain) = self.config.gain.finer() {
+            self.autorange_small_count += 1;
+            if self.autorange_small_count >= AUTORANGE_STEP_UP_SAMPLES {
+                self.autorange_small_count = 0;
+                sample = self.change_gain_and_read(gain, delay_source)?;
+            }
+        }
+        Ok(sample)
+    }
+
+    /// Change the gain and read a sample taken at the new gain. The gain
+    /// applies from the second measurement after the change, so the first
+    /// is discarded.
+    fn change_gain_and_read(
+        &mut self,
+        gain: GainSetting,
+        delay_source: &mut impl DelayMs<u8>,
+    ) -> Result<[i16; 3], crate::Error<CommE, PinE>> {
+        self.set_gain(gain)?;
+        self.discard_warmup_samples(delay_source)?;
+        Self::delay_ms_long(delay_source, self.settling_time_ms());
+        self.read_mag_vector_with_policy(RangePolicy::PassThrough)
+    }
+
+    fn is_saturated(sample: &[i16; 3]) -> bool {
+        sample
+            .iter()
+            .any(|v| v.saturating_abs() >= OVERFLOW_VALUE.saturating_abs())
+    }
+
+    /// Fill `buf` with consecutive samples at the configured ODR, waiting on
+    /// the Status register for each new sample.
+    /// Saturated samples are stored as read (axes at the overflow value),
+    /// whatever the range policy, rather than ending the burst. Returns the number of samples captured;
+    /// an error ends the burst early, and is returned only if no samples
+    /// were captured.
+    pub fn capture_burst(
+        &mut self,
+        buf: &mut [[i16; 3]],
+        delay_source: &mut impl DelayUs<u16>,
+    ) -> Result<usize, crate::Error<CommE, PinE>> {
+        let timeout_ms = self.sample_timeout_ms();
+        for (captured, slot) in buf.iter_mut().enumerate() {
+            match self.wait_and_read(
+                None::<&NoDrdyPin>,
+                timeout_ms,
+                RangePolicy::PassThrough,
+                delay_source,
+            ) {
+                Ok(sample) => *slot = sample,
+                Err(e) if captured == 0 => return Err(e),
+                Err(_) => return Ok(captured),
+            }
+        }
+        Ok(buf.len())
+    }
+
+    /// Average `N` consecutive samples in software, waiting on the Status
+    /// register for each. The window is fixed at compile time, so the
+    /// blocking duration is predictable: about `N` output periods.
+    pub fn get_mag_vector_oversampled<const N: usize>(
+        &mut self,
+        delay_source: &mut impl DelayUs<u16>,
+    ) -> Result<[i16; 3], crate::Error<CommE, PinE>> {
+        if N == 0 {
+            return Err(Error::Configuration);
+        }
+        let timeout_ms = self.sample_timeout_ms();
+        // i64 sums cannot overflow for any N that fits in memory
+        let mut sums = [0i64; 3];
+        for _ in 0..N {
+            let sample = self.wait_and_read(
+                None::<&NoDrdyPin>,
+                timeout_ms,
+                self.range_policy,
+                delay_source,
+            )?;
+            for (sum, val) in sums.iter_mut().zip(sample.iter()) {
+                *sum += *val as i64;
+            }
+        }
+        Ok([
+            (sums[0] / N as i64) as i16,
+            (sums[1] / N as i64) as i16,
+            (sums[2] / N as i64) as i16,
+        ])
+    }
+
+    /// Expected time for `n` software-averaged samples, in microseconds:
+    /// one output period per sample, so `n * 1e6 / ODR`. At 0.75 Hz, for
+    /// example, 16 samples take over 21 seconds.
+    pub fn oversampling_duration_us(&self, n: usize) -> u32 {
+        (n as u32).saturating_mul(self.measurement_period_us())
+    }
+
+    /// Like `get_mag_vector_oversampled`, but first checks that the `N`
+    /// samples are expected to take no longer than `budget_ms` at the
+    /// configured ODR (see `oversampling_duration_us`), returning
+    /// `Error::Configuration` without reading if not. Use this where a
+    /// watchdog can't tolerate an unexpectedly long blocking average.
+    pub fn get_mag_vector_oversampled_within<const N: usize>(
+        &mut self,
+        budget_ms: u32,
+        delay_source: &mut impl DelayUs<u16>,
+    ) -> Result<[i16; 3], crate::Error<CommE, PinE>> {
+        if self.oversampling_duration_us(N) > budget_ms.saturating_mul(1000) {
+            return Err(Error::Configuration);
+        }
+        self.get_mag_vector_oversampled::<N>(delay_source)
+    }
+
+    /// Average 16 samples in software; see `get_mag_vector_oversampled`
+    pub fn get_mag_vector_oversampled_16(
+        &mut self,
+        delay_source: &mut impl DelayUs<u16>,
+    ) -> Result<[i16; 3], crate::Error<CommE, PinE>> {
+        self.get_mag_vector_oversampled::<16>(delay_source)
+    }
+
+    /// Measure sensor noise: take `n` consecutive samples (at least 2) while
+    /// the sensor is held still, and return the per-axis standard deviation
+    /// in raw counts.
+    pub fn measure_noise(
+        &mut self,
+        n: usize,
+        delay_source: &mut impl DelayUs<u16>,
+    ) -> Result<[f32; 3], crate::Error<CommE, PinE>> {
+        if n < 2 {
+            return Err(Error::Configuration);
+        }
+        let timeout_ms = self.sample_timeout_ms();
+        let mut sums = [0f64; 3];
+        let mut sums_sq = [0f64; 3];
+        for _ in 0..n {
+            let sample = self.wait_and_read(
+                None::<&NoDrdyPin>,
+                timeout_ms,
+                self.range_policy,
+                delay_source,
+            )?;
+            for i in 0..3 {
+                let val = sample[i] as f64;
+                sums[i] += val;
+                sums_sq[i] += val * val;
+            }
+        }
+        let count = n as f64;
+        let mut std_dev = [0f32; 3];
+        for i in 0..3 {
+            let mean = sums[i] / count;
+            let variance = (sums_sq[i] - count * mean * mean) / (count - 1.0);
+            std_dev[i] = math::sqrtf(variance.max(0.0) as f32);
+        }
+        Ok(std_dev)
+    }
+
+    /// Measure the rate at which the sensor is actually producing samples,
+    /// in Hz, by timing Status RDY transitions over `window_ms`.
+    /// `now_us` is a free-running microsecond clock (wrapping is handled).
+    /// Status is polled every `STATUS_POLL_INTERVAL_US` using
+    /// `delay_source`. A sample already waiting when this is called is
+    /// discarded, since its arrival time is unknown.
+    /// Compare the result against `measurement_period_us` to catch a
+    /// sensor left at the wrong output rate.
+    /// Returns `Error::Timeout` if fewer than two samples arrive in the window.
+    pub fn measure_sample_rate(
+        &mut self,
+        window_ms: u16,
+        mut now_us: impl FnMut() -> u32,
+        delay_source: &mut impl DelayUs<u16>,
+    ) -> Result<f32, crate::Error<CommE, PinE>> {
+        if self.poll_status_ready()? {
+            self.read_raw_vector()?;
+        }
+        let window_us = window_ms as u32 * 1000;
+        let start = now_us();
+        let mut first: Option<u32> = None;
+        let mut last = start;
+        let mut count: u32 = 0;
+        loop {
+            let now = now_us();
+            if now.wrapping_sub(start) >= window_us {
+                break;
+            }
+            if self.poll_status_ready()? {
+                // reading the data clears RDY until the next sample
+                self.read_raw_vector()?;
+                first.get_or_insert(now);
+                last = now;
+                count += 1;
+            } else {
+                delay_source.delay_us(STATUS_POLL_INTERVAL_US);
+            }
+        }
+        match first {
+            Some(first) if count >= 2 && last != first => {
+                let elapsed_us = last.wrapping_sub(first) as f32;
+                Ok((count - 1) as f32 * 1_000_000.0 / elapsed_us)
+            }
+            _ => Err(Error::Timeout),
+        }
+    }
+
+    /// Expected sensor noise (one standard deviation) in microtesla for the
+    /// current gain and averaging, to compare against `measure_noise`
+    /// (after scaling its counts by the gain). Assumes the datasheet noise
+    /// floor of 2 mG (0.2 µT) at the finest gains, but never below one
+    /// count at coarser ones, reduced by √N for N averaged samples.
+    /// Measured noise well above this suggests environmental interference.
+    pub fn noise_floor_microtesla(&self) -> f32 {
+        // 1 gauss = 100 microtesla
+        let resolution_ut = 100.0 / self.config.gain.lsb_per_gauss() as f32;
+        let floor_ut = resolution_ut.max(NOISE_FLOOR_MICROTESLA);
+        floor_ut / math::sqrtf(self.config.averaging.count() as f32)
+    }
+
+    /// Read the mag vector in microtesla as Q16.16 fixed-point values,
+    /// using integer math only (for targets without an FPU).
+    /// Values are truncated toward zero.
+    #[cfg(feature = "fixed")]
+    pub fn get_mag_vector_q16(
+        &mut self,
+    ) -> Result<[I16F16; 3], crate::Error<CommE, PinE>> {
+        let raw = self.get_mag_vector()?;
+        // 1 gauss = 100 microtesla
+        let lsb_per_gauss = self.config.gain.lsb_per_gauss() as i64;
+        let mut out = [I16F16::ZERO; 3];
+        for (dst, src) in out.iter_mut().zip(raw.iter()) {
+            let bits = (((*src as i64) * 100) << 16) / lsb_per_gauss;
+            *dst = I16F16::from_bits(bits as i32);
+        }
+        Ok(out)
+    }
+
+    /// Read the mag vector in milligauss, rounded to the nearest integer,
+    /// using integer math only. Matches `get_mag_vector_scaled` (which is
+    /// in microtesla, 1 mG = 0.1 µT) to within rounding.
+    pub fn get_mag_milligauss(
+        &mut self,
+    ) -> Result<[i32; 3], crate::Error<CommE, PinE>> {
+        let raw = self.get_mag_vector()?;
+        let lsb_per_gauss = self.config.gain.lsb_per_gauss();
+        let mut out = [0i32; 3];
+        for (dst, src) in out.iter_mut().zip(raw.iter()) {
+            *dst = counts_to_milligauss(*src, lsb_per_gauss);
+        }
+        Ok(out)
+    }
+
+    /// Read Config A through the Status register (0x00..=0x09).
+    /// Over SPI this is one transaction using register address
+    /// auto-increment. Over I2C, config and data (0x00..=0x08) are read in
+    /// one transaction and Status separately, because the pointer rolls back
+    /// from 0x08 to 0x03 instead of advancing to Status.
+    /// The returned bytes are indexed by register address.
+    pub fn read_config_data_status_raw(
+        &mut self,
+    ) -> Result<[u8; CONFIG_DATA_STATUS_LEN], crate::Error<CommE, PinE>> {
+        const CONFIG_DATA_LEN: usize = CONFIG_DATA_STATUS_LEN - 1;
+        let mut regs = [0u8; CONFIG_DATA_STATUS_LEN];
+        if SI::STATUS_FOLLOWS_DATA {
+            self.read_block(Register::ConfigA, CONFIG_DATA_STATUS_LEN)?;
+            regs.copy_from_slice(&self.block_buf[..CONFIG_DATA_STATUS_LEN]);
+        } else {
+            self.read_block(Register::ConfigA, CONFIG_DATA_LEN)?;
+            regs[..CONFIG_DATA_LEN]
+                .copy_from_slice(&self.block_buf[..CONFIG_DATA_LEN]);
+            regs[Register::Status as usize] =
+                self.read_reg(Register::Status)?;
+        }
+        Ok(regs)
+    }
+
+    /// Read the whole device state in as few transactions as the bus
+    /// allows: config, data and Status (see `read_config_data_status_raw`),
+    /// then the temperature output (HMC5983 only) in one more.
+    /// Costs less bus time than calling the individual getters.
+    pub fn read_full_state(
+        &mut self,
+    ) -> Result<FullState, crate::Error<CommE, PinE>> {
+        let regs = self.read_config_data_status_raw()?;
+        let data = &regs[REG_MAG_DATA_START as usize..];
+        let mag = self.decode_mag_data(data);
+        let temperature = match self.variant {
+            Variant::Hmc5983 => Some(self.get_temperature()?),
+            Variant::Hmc5883 => None,
+        };
+        Ok(FullState {
+            config_a: regs[Register::ConfigA as usize],
+            config_b: regs[Register::ConfigB as usize],
+            config_c: regs[Register::ConfigC as usize],
+            mag,
+            status: Status::decode_status(regs[Register::Status as usize]),
+            temperature,
+        })
+    }
+
+    /// Capture status, mag data and temperature in back-to-back reads,
+    /// along with the active gain and ODR, as one record for logging
+    pub fn read_snapshot(
+        &mut self,
+    ) -> Result<Snapshot, crate::Error<CommE, PinE>> {
+        let status = Status::decode_status(self.read_reg(Register::Status)?);
+        let mag = self.get_mag_vector()?;
+        let temperature = match self.variant {
+            Variant::Hmc5983 => Some(self.get_temperature()?),
+            Variant::Hmc5883 => None,
+        };
+        Ok(Snapshot {
+            mag,
+            temperature,
+            status,
+            gain: self.config.gain,
+            odr: self.config.odr,
+        })
+    }
+
+    /// Read the mag vector scaled to microtesla using the current gain
+    pub fn get_mag_vector_scaled(
+        &mut self,
+    ) -> Result<[f32; 3], crate::Error<CommE, PinE>> {
+        let mut scaled = [0.0; 3];
+        self.read_mag_vector_scaled_into(&mut scaled)?;
+        Ok(scaled)
+    }
+
+    /// Read the mag vector scaled to microtesla into a caller-supplied buffer
+    pub fn read_mag_vector_scaled_into(
+        &mut self,
+        out: &mut [f32; 3],
+    ) -> Result<(), crate::Error<CommE, PinE>> {
+        let raw = self.get_mag_vector()?;
+        let ut_per_lsb = self.config.gain.describe().resolution_ut_per_lsb;
+        for (dst, src) in out.iter_mut().zip(raw.iter()) {
+            *dst = (*src as f32) * ut_per_lsb;
+        }
+        Ok(())
+    }
+
+    /// Read the raw temperature output registers: `[MSB, LSB]`,
+    /// read as one block starting at `Register::TempMsb`
+    pub fn get_temperature_raw_bytes(
+        &mut self,
+    ) -> Result<[u8; 2], crate::Error<CommE, PinE>> {
+        // the temperature registers do not exist on the HMC5883
+        if self.variant != Variant::Hmc5983 {
+            return Err(Error::Unsupported);
+        }
+
+        self.read_block(Register::TempMsb, TEMP_DATA_LEN)?;
+        Ok([self.block_buf[0], self.block_buf[1]])
+    }
+
+    /// Set the hard-iron offset (microtesla), subtracted from each reading.
+    /// Non-finite values are rejected with `Error::Configuration`.
+    pub fn set_hard_iron_offset(
+        &mut self,
+        offset: [f32; 3],
+    ) -> Result<(), crate::Error<CommE, PinE>> {
+        if !all_finite(&offset) {
+            return Err(Error::Configuration);
+        }
+        self.calibration.hard_iron = offset;
+        Ok(())
+    }
+
+    /// Set the soft-iron correction matrix.
+    /// Non-finite values are rejected with `Error::Configuration`.
+    pub fn set_soft_iron_matrix(
+        &mut self,
+        matrix: [[f32; 3]; 3],
+    ) -> Result<(), crate::Error<CommE, PinE>> {
+        if !matrix.iter().all(|row| all_finite(row)) {
+            return Err(Error::Configuration);
+        }
+        self.calibration.soft_iron = matrix;
+        Ok(())
+    }
+
+    /// Set the per-axis scale factors.
+    /// Non-finite values are rejected with `Error::Configuration`.
+    pub fn set_axis_scale(
+        &mut self,
+        scale: [f32; 3],
+    ) -> Result<(), crate::Error<CommE, PinE>> {
+        if !all_finite(&scale) {
+            return Err(Error::Configuration);
+        }
+        self.calibration.axis_scale = scale;
+        Ok(())
+    }
+
+    /// The software calibration currently applied
+    pub fn calibration(&self) -> Calibration {
+        self.calibration
+    }
+
+    /// Reset the software calibration and the mounting rotation to pass
+    /// readings through unchanged: zero hard-iron offset, identity soft-iron
+    /// and mounting matrices, and unit axis scales. The EMA filter state
+    /// is discarded too (see `set_ema_alpha`). The chip is not touched.
+    pub fn clear_calibration(&mut self) {
+        self.calibration = Calibration::IDENTITY;
+        self.mounting_rotation = IDENTITY_MATRIX;
+        self.ema_state = None;
+    }
+
+    /// Set the rotation from the sensor frame into the body frame, such as
+    /// the current joint angles of a gimbal the sensor rides on.
+    /// This is a frame change, kept separate from the soft-iron matrix
+    /// so that each can be updated independently. Defaults to identity.
+    /// Non-finite values are rejected with `Error::Configuration`.
+    pub fn set_mounting_rotation(
+        &mut self,
+        rot: [[f32; 3]; 3],
+    ) -> Result<(), crate::Error<CommE, PinE>> {
+        if !rot.iter().all(|row| all_finite(row)) {
+            return Err(Error::Configuration);
+        }
+        self.mounting_rotation = rot;
+        Ok(())
+    }
+
+    /// The sensor-to-body mounting rotation currently applied
+    pub fn mounting_rotation(&self) -> [[f32; 3]; 3] {
+        self.mounting_rotation
+    }
+
+    /// Read the mag vector in microtesla with the software calibration
+    /// applied, then rotated into the body frame by the mounting rotation,
+    /// and smoothed by the EMA filter if one is set (see `set_ema_alpha`)
+    pub fn get_mag_vector_calibrated(
+        &mut self,
+    ) -> Result<[f32; 3], crate::Error<CommE, PinE>> {
+        let body = self.read_calibrated_unfiltered()?;
+        Ok(self.apply_ema(body))
+    }
+
+    /// `get_mag_vector_calibrated` without the EMA filter
+    fn read_calibrated_unfiltered(
+        &mut self,
+    ) -> Result<[f32; 3], crate::Error<CommE, PinE>> {
+        let scaled = self.get_mag_vector_scaled()?;
+        let corrected = self.calibration.apply(scaled);
+        Ok(mat_mul_vec(&self.mounting_rotation, corrected))
+    }
+
+    /// Smooth `get_mag_vector_calibrated` with an exponential moving
+    /// average per axis: `out = alpha * new + (1 - alpha) * prev`.
+    /// Smaller values smooth more but lag further behind rotation.
+    /// Defaults to 1.0 (no filtering).
+    /// The filter applies only to the calibrated read and what is built on
+    /// it: `get_heading_degrees`, `compass_heading`, `yaw_radians` and
+    /// `get_inclination_degrees`. Raw and scaled reads,
+    /// `get_heading_degrees_int` and `calibration_residual` are unfiltered.
+    /// Values outside 0..=1 are rejected with `Error::Configuration`.
+    /// The filter state is kept, so the output doesn't jump.
+    pub fn set_ema_alpha(
+        &mut self,
+        alpha: f32,
+    ) -> Result<(), crate::Error<CommE, PinE>> {
+        if !(0.0..=1.0).contains(&alpha) {
+            return Err(Error::Configuration);
+        }
+        self.ema_alpha = alpha;
+        Ok(())
+    }
+
+    /// Discard the EMA filter state, so the next calibrated read starts
+    /// the average afresh, for example after a large calibration change
+    pub fn reset_ema(&mut self) {
+        self.ema_state = None;
+    }
+
+    /// Fold a sample into the EMA filter; the first sample seeds it
+    fn apply_ema(&mut self, sample: [f32; 3]) -> [f32; 3] {
+        let alpha = self.ema_alpha;
+        let out = match self.ema_state {
+            Some(prev) => [
+                alpha * sample[0] + (1.0 - alpha) * prev[0],
+                alpha * sample[1] + (1.0 - alpha) * prev[1],
+                alpha * sample[2] + (1.0 - alpha) * prev[2],
+            ],
+            None => sample,
+        };
+        self.ema_state = Some(out);
+        out
+    }
+
+    /// Measure calibration quality: collect `n` calibrated samples, one
+    /// output period apart, while the user rotates the device, and return
+    /// the RMS deviation of their magnitudes from the mean, in microtesla.
+    /// A good calibration puts every reading on a sphere, so the residual is
+    /// close to the noise level; a large one means calibration should be
+    /// redone. `n` must be at least 2, else `Error::Configuration`.
+    /// The EMA filter is bypassed, since it would pull the magnitudes of
+    /// rotating readings towards each other.
+    pub fn calibration_residual(
+        &mut self,
+        n: usize,
+        delay_source: &mut impl DelayMs<u8>,
+    ) -> Result<f32, crate::Error<CommE, PinE>> {
+        if n < 2 {
+            return Err(Error::Configuration);
+        }
+        let period_ms = self.measurement_period_us().div_ceil(1000);
+        let mut sum = 0f64;
+        let mut sum_sq = 0f64;
+        for _ in 0..n {
+            Self::delay_ms_long(delay_source, period_ms);
+            let [x, y, z] = self.read_calibrated_unfiltered()?;
+            let magnitude = math::sqrtf(x * x + y * y + z * z) as f64;
+            sum += magnitude;
+            sum_sq += magnitude * magnitude;
+        }
+        let count = n as f64;
+        let mean = sum / count;
+        let variance = sum_sq / count - mean * mean;
+        Ok(math::sqrtf(variance.max(0.0) as f32))
+    }
+
+    /// Choose which horizontal body axis (±X or ±Y) is "forward" for
+    /// heading. Defaults to +X. Z axes are rejected with
+    /// `Error::Configuration`.
+    pub fn set_heading_reference(
+        &mut self,
+        axis: AxisSource,
+    ) -> Result<(), crate::Error<CommE, PinE>> {
+        if axis.axis_index() == 2 {
+            return Err(Error::Configuration);
+        }
+        self.heading_reference = axis;
+        Ok(())
+    }
+
+    /// Set the local magnetic declination in degrees: positive when magnetic
+    /// north is east of true north, negative when west.
+    /// Once set, headings are relative to true (geographic) north.
+    /// Defaults to zero (magnetic headings).
+    pub fn set_declination_degrees(
+        &mut self,
+        deg: f32,
+    ) -> Result<(), crate::Error<CommE, PinE>> {
+        if !deg.is_finite() {
+            return Err(Error::Configuration);
+        }
+        self.declination_deg = deg;
+        Ok(())
+    }
+
+    /// Heading of the reference axis in degrees (0..360, clockwise from
+    /// north), from the calibrated reading. Relative to magnetic north
+    /// unless a declination is set, in which case it is relative to true
+    /// north. Assumes the sensor is level; see `to_ned` for the body frame.
+    pub fn get_heading_degrees(
+        &mut self,
+    ) -> Result<f32, crate::Error<CommE, PinE>> {
+        let field = self.get_mag_vector_calibrated()?;
+        Ok(self.heading_from_field(field))
+    }
+
+    /// The one-call compass: read the field, apply the calibration and
+    /// mounting rotation, and return the heading in degrees (0..360,
+    /// clockwise from north) with the declination applied. Configure those
+    /// up front with the calibration setters and `set_declination_degrees`.
+    /// The same as `get_heading_degrees`.
+    /// Assumes the sensor is level. On a platform that tilts, keep the
+    /// mounting rotation updated from the current attitude (see
+    /// `set_mounting_rotation`) so that the field is levelled first.
+    pub fn compass_heading(
+        &mut self,
+    ) -> Result<f32, crate::Error<CommE, PinE>> {
+        self.get_heading_degrees()
+    }
+
+    /// Yaw in radians (0..2π, clockwise from north) for a sensor that is
+    /// always mounted flat, such as on a ground rover. No tilt compensation
+    /// is applied, so the result is wrong whenever the platform pitches or
+    /// rolls. Uses the heading reference axis and declination, like
+    /// `get_heading_degrees`.
+    pub fn yaw_radians(&mut self) -> Result<f32, crate::Error<CommE, PinE>> {
+        Ok(self.get_heading_degrees()?.to_radians())
+    }
+
+    /// Magnetic inclination (dip angle) in degrees, -90..=90:
+    /// `atan2(-z, sqrt(x² + y²))` of the calibrated body-frame field.
+    /// The body frame is +Z up (see `to_ned`), so z is negated to follow
+    /// the usual convention: positive when the field points downward, as
+    /// in the northern hemisphere. Once calibrated, this should match the
+    /// expected dip at your location, which makes it a useful check of
+    /// calibration quality.
+    pub fn get_inclination_degrees(
+        &mut self,
+    ) -> Result<f32, crate::Error<CommE, PinE>> {
+        let [x, y, z] = self.get_mag_vector_calibrated()?;
+        let horizontal = math::sqrtf(x * x + y * y);
+        Ok(math::atan2f(-z, horizontal).to_degrees())
+    }
+
+    /// Heading in whole degrees (0..=359) using integer math only, for
+    /// targets that can't afford float atan2. Uses the remapped raw reading
+    /// and the heading reference axis; the software calibration, mounting
+    /// rotation and declination are not applied.
+    pub fn get_heading_degrees_int(
+        &mut self,
+    ) -> Result<u16, crate::Error<CommE, PinE>> {
+        let [x, y, _] = self.get_mag_vector()?;
+        let (forward, left) = self.heading_axes(x as i32, y as i32);
+        // the axes of an i16 reading negate to at most 32768
+        let (forward, left) =
+            (forward.min(i16::MAX as i32), left.min(i16::MAX as i32));
+        Ok(math::atan2_degrees_int(left, forward))
+    }
+
+    /// Forward and left components relative to the heading reference axis
+    fn heading_axes<T: core::ops::Neg<Output = T>>(
+        &self,
+        x: T,
+        y: T,
+    ) -> (T, T) {
+        match self.heading_reference {
+            AxisSource::PlusY => (y, -x),
+            AxisSource::MinusX => (-x, -y),
+            AxisSource::MinusY => (-y, x),
+            _ => (x, y),
+        }
+    }
+
+    /// Heading in degrees of the reference axis for a body-frame field
+    fn heading_from_field(&self, field: [f32; 3]) -> f32 {
+        let [x, y, _] = field;
+        let (forward, left) = self.heading_axes(x, y);
+        let heading_rad = math::atan2f(left, forward);
+        math::wrap_degrees(heading_rad + self.declination_deg.to_radians())
+    }
+
+    /// Read temperature from device
+    /// Result is degrees Celsius
+    pub fn get_temperature(
+        &mut self,
+    ) -> Result<i16, crate::Error<CommE, PinE>> {
+        let raw_bytes = self.get_temperature_raw_bytes()?;
+
+        // Two's complement, MSB first like the mag data registers.
+        let raw = combine_be(raw_bytes[0], raw_bytes[1]);
+        let scale = self.temperature_scale;
+        let celsius = raw as i32 / scale.lsb_per_degree as i32
+            + scale.offset_celsius as i32;
+        Ok(celsius.clamp(i16::MIN as i32, i16::MAX as i32) as i16)
+    }
+}
+
+/// Convert raw counts to milligauss, rounding half away from zero
+const fn counts_to_milligauss(raw: i16, lsb_per_gauss: u16) -> i32 {
+    let numerator = raw as i32 * 1000;
+    let half = lsb_per_gauss as i32 / 2;
+    let rounded = if numerator < 0 {
+        numerator - half
+    } else {
+        numerator + half
+    };
+    rounded / lsb_per_gauss as i32
+}
+
+const _: () = assert!(counts_to_milligauss(1090, 1090) == 1000);
+const _: () = assert!(counts_to_milligauss(-545, 1090) == -500);
+// 100 / 1370 G = 72.99 mG
+const _: () = assert!(counts_to_milligauss(100, 1370) == 73);
+const _: () = assert!(counts_to_milligauss(-100, 1370) == -73);
+// full negative scale at the coarsest gain: -4096 / 230 G = -17808.7 mG
+const _: () = assert!(counts_to_milligauss(-4096, 230) == -17809);
+
+/// Configuration applied by `init`
+const INIT_CONFIG: Config = Config {
+    mode: MeasurementModeSetting::NormalMode,
+    odr: OdrSetting::Odr30_0Hz,
+    averaging: SampleAvgSetting::AvgSamples8,
+    temp_enabled: true,
+    gain: GainSetting::Gain0820,
+    operating_mode: OperatingMode::Continuous,
+};
+
+// Guard the Config A encoding written by `init`:
+// temperature on (0x80) | 8 averaged (0x60) | 30 Hz (0x14) | normal (0x00)
+const _: () = assert!(
+    encode_config_a(
+        INIT_CONFIG.mode,
+        INIT_CONFIG.odr,
+        INIT_CONFIG.averaging,
+        INIT_CONFIG.temp_enabled
+    ) == 0xF4
+);
+
+// Guard the Config C (Mode register) byte written by `init`:
+// MD1:MD0 = 00 selects continuous measurement
+const _: () = assert!(INIT_CONFIG.operating_mode as u8 == 0x00);
+
+/// Register to read out all three dimensions of mag data
+const REG_MAG_DATA_START: Register = Register::DataX;
+
+const BLOCK_BUF_LEN: usize = 32;
+
+/// Bytes in a mag data read: X, Z, Y, each MSB then LSB
+const MAG_DATA_LEN: usize = 6;
+
+/// Bytes in a mag data plus Status read, where the interface allows it
+const DATA_STATUS_LEN: usize = MAG_DATA_LEN + 1;
+
+/// Bytes in a temperature read: MSB then LSB
+const TEMP_DATA_LEN: usize = 2;
+
+/// Bytes in the identification read: ID registers A, B, C
+const ID_LEN: usize = 3;
+
+/// Contents of ID registers A, B, C on a genuine chip
+pub const EXPECTED_ID: [u8; ID_LEN] = *b"H43";
+
+/// The default ID matcher: accepts exactly `EXPECTED_ID`
+pub fn is_expected_id(id: &[u8; 3]) -> bool {
+    *id == EXPECTED_ID
+}
+
+/// Whether ID bytes look like the genuine ID sampled on the wrong clock
+/// edge (the 24-bit stream shifted by one bit either way), or like a MISO
+/// line that is floating or stuck (all 0x00 or all 0xFF)
+const fn looks_like_wrong_spi_mode(id: &[u8; 3]) -> bool {
+    const MASK: u32 = 0x00FF_FFFF;
+    let expected =
+        u32::from_be_bytes([0, EXPECTED_ID[0], EXPECTED_ID[1], EXPECTED_ID[2]]);
+    let read = u32::from_be_bytes([0, id[0], id[1], id[2]]);
+    // ignore the bit shifted in at either end, which is unknown
+    let shifted_left = (read & !1) == ((expected << 1) & MASK & !1);
+    let shifted_right = (read & (MASK >> 1)) == (expected >> 1);
+    read == 0 || read == MASK || shifted_left || shifted_right
+}
+
+/// Offsets of each axis within a data block read from `Register::DataX`
+const DATA_X_OFFSET: usize =
+    (Register::DataX as usize) - (REG_MAG_DATA_START as usize);
+const DATA_Z_OFFSET: usize =
+    (Register::DataZ as usize) - (REG_MAG_DATA_START as usize);
+const DATA_Y_OFFSET: usize =
+    (Register::DataY as usize) - (REG_MAG_DATA_START as usize);
+
+/// Status register: data ready
+const STATUS_RDY: u8 = 1 << 0;
+
+/// Status register: output registers locked
+const STATUS_LOCK: u8 = 1 << 1;
+
+/// Interval between DRDY polls: short enough to catch its 250 µs low pulse
+const DRDY_POLL_INTERVAL_US: u16 = 100;
+
+/// Interval between Status register polls
+const STATUS_POLL_INTERVAL_US: u16 = 1000;
+
+/// Samples discarded after entering continuous mode.
+/// Datasheet, Configuration Register B: "The new gain setting is effective
+/// from the second measurement and on."
+pub const CONTINUOUS_WARMUP_SAMPLES: u8 = 1;
+
+/// Default number of ID check retries
+pub const DEFAULT_ID_CHECK_RETRIES: u8 = 3;
+
+/// Delay between ID check attempts
+pub const ID_CHECK_RETRY_DELAY_MS: u8 = 5;
+
+/// Gain used for the self-test
+const SELF_TEST_GAIN: GainSetting = GainSetting::Gain0390;
+/// Minimum self-test reading at `SELF_TEST_GAIN` (datasheet)
+const SELF_TEST_LOW_LIMIT: i16 = 243;
+/// Maximum self-test reading at `SELF_TEST_GAIN` (datasheet)
+const SELF_TEST_HIGH_LIMIT: i16 = 575;
+/// Largest allowed difference between the positive and negative self-test
+/// responses of an axis, as a percentage of the positive response.
+/// Not a datasheet limit: a healthy part is symmetric to within a few
+/// percent, so this only flags a clearly damaged bias strap or axis.
+pub const SELF_TEST_SYMMETRY_PERCENT: i32 = 10;
+
+/// Datasheet noise floor without averaging (2 milligauss), in microtesla
+const NOISE_FLOOR_MICROTESLA: f32 = 0.2;
+
+/// Output value reported by an axis when the ADC overflows (0xF000)
+const OVERFLOW_VALUE: i16 = -4096;
+
+/// Largest valid output value
+const FULL_SCALE: i16 = 2047;
+
+/// Readings below this magnitude use under 1/8 of the output range
+const AUTORANGE_SMALL: i16 = FULL_SCALE / 8;
+
+/// Consecutive small readings before auto-range steps to a finer gain
+pub const AUTORANGE_STEP_UP_SAMPLES: u8 = 8;
+
+/// Number of registers from Config A through Status, inclusive.
+/// Well under the 31-byte limit of a single SPI block read.
+pub const CONFIG_DATA_STATUS_LEN: usize = 10;
+
+/// Time for a single measurement to complete (datasheet: 6 ms)
+const SINGLE_MEASUREMENT_DELAY_MS: u8 = 6;
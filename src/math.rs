@@ -0,0 +1,117 @@
+/*
+Copyright (c) 2020 Todd Stellanova
+LICENSE: BSD3 (see LICENSE file)
+*/
+
+//! Minimal float math for `no_std` targets without `libm`
+
+/// Square root: bit-level initial estimate refined by Newton's method
+pub(crate) fn sqrtf(x: f32) -> f32 {
+    if x.is_nan() || x < 0.0 {
+        return f32::NAN;
+    }
+    if x == 0.0 || x.is_infinite() {
+        return x;
+    }
+    let mut y = f32::from_bits((x.to_bits() >> 1) + 0x1fbd_1df5);
+    for _ in 0..4 {
+        y = 0.5 * (y + x / y);
+    }
+    y
+}
+
+/// Arctangent for |x| <= 1, minimax polynomial (max error about 1e-5 rad)
+fn atan_unit(x: f32) -> f32 {
+    let x2 = x * x;
+    x * (0.999_977_3
+        + x2 * (-0.332_623_5
+            + x2 * (0.193_543_5
+                + x2 * (-0.116_432_9 + x2 * (0.052_653_3 - x2 * 0.011_721_2)))))
+}
+
+/// Four-quadrant arctangent of `y / x`, in radians (-pi..=pi)
+pub(crate) fn atan2f(y: f32, x: f32) -> f32 {
+    use core::f32::consts::{FRAC_PI_2, PI};
+    if x == 0.0 && y == 0.0 {
+        return 0.0;
+    }
+    let (ax, ay) = (x.abs(), y.abs());
+    let mut angle = if ax >= ay {
+        atan_unit(ay / ax)
+    } else {
+        FRAC_PI_2 - atan_unit(ax / ay)
+    };
+    if x < 0.0 {
+        angle = PI - angle;
+    }
+    if y < 0.0 {
+        -angle
+    } else {
+        angle
+    }
+}
+
+/// Convert radians to degrees wrapped into 0..360
+pub(crate) fn wrap_degrees(radians: f32) -> f32 {
+    let mut degrees = radians.to_degrees();
+    while degrees < 0.0 {
+        degrees += 360.0;
+    }
+    while degrees >= 360.0 {
+        degrees -= 360.0;
+    }
+    degrees
+}
+
+/// CORDIC rotation angles `atan(2^-i)` in 1/256 degree units
+const CORDIC_ANGLES_Q8: [i32; 15] = [
+    11520, 6801, 3593, 1824, 916, 458, 229, 115, 57, 29, 14, 7, 4, 2, 1,
+];
+
+/// Fractional bits added to the inputs so the CORDIC shifts keep precision;
+/// i16 inputs plus the CORDIC gain (about 1.65) still fit in an i32
+const CORDIC_INPUT_SHIFT: u32 = 12;
+
+/// Four-quadrant arctangent of `y / x` using integer CORDIC only,
+/// rounded to whole degrees 0..360 (counter-clockwise from +x).
+/// Input magnitudes must be at most 32768, the range of a negated i16.
+pub(crate) const fn atan2_degrees_int(y: i32, x: i32) -> u16 {
+    if x == 0 && y == 0 {
+        return 0;
+    }
+    // rotate into the right half-plane, where CORDIC converges
+    let (mut x, mut y, mut angle) = if x < 0 {
+        (
+            -x << CORDIC_INPUT_SHIFT,
+            -y << CORDIC_INPUT_SHIFT,
+            180 * 256,
+        )
+    } else {
+        (x << CORDIC_INPUT_SHIFT, y << CORDIC_INPUT_SHIFT, 0)
+    };
+    let mut i = 0;
+    while i < CORDIC_ANGLES_Q8.len() {
+        let (dx, dy) = (x >> i, y >> i);
+        if y > 0 {
+            x += dy;
+            y -= dx;
+            angle += CORDIC_ANGLES_Q8[i];
+        } else {
+            x -= dy;
+            y += dx;
+            angle -= CORDIC_ANGLES_Q8[i];
+        }
+        i += 1;
+    }
+    ((angle + 128) >> 8).rem_euclid(360) as u16
+}
+
+const _: () = assert!(atan2_degrees_int(0, 1000) == 0);
+const _: () = assert!(atan2_degrees_int(1000, 1000) == 45);
+const _: () = assert!(atan2_degrees_int(1000, 577) == 60);
+const _: () = assert!(atan2_degrees_int(1000, 0) == 90);
+const _: () = assert!(atan2_degrees_int(0, -1000) == 180);
+const _: () = assert!(atan2_degrees_int(-1000, 0) == 270);
+const _: () = assert!(atan2_degrees_int(-1000, 1000) == 315);
+const _: () = assert!(atan2_degrees_int(-32768, -32768) == 225);
+const _: () = assert!(atan2_degrees_int(-1, 32767) == 0);
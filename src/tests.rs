@@ -0,0 +1,811 @@
+/*
+Copyright (c) 2020 Todd Stellanova
+LICENSE: BSD3 (see LICENSE file)
+*/
+
+//! Driver tests against the simulated sensor
+
+use crate::interface::{SimClock, SimInterface, SpiInterface};
+use crate::*;
+
+/// Delay source for simulations without a clock
+struct NoDelay;
+
+impl DelayMs<u8> for NoDelay {
+    fn delay_ms(&mut self, _ms: u8) {}
+}
+
+impl DelayUs<u16> for NoDelay {
+    fn delay_us(&mut self, _us: u16) {}
+}
+
+/// A driver on an untimed simulation of `samples`, after `init`
+fn init_sim(samples: &[[i16; 3]]) -> HMC5983<SimInterface<'_>> {
+    let mut mag =
+        HMC5983::new_with_interface(SimInterface::new_with_samples(samples));
+    mag.init(&mut NoDelay).unwrap();
+    mag
+}
+
+#[test]
+fn sim_script_starts_after_init() {
+    let samples = [[1, 2, 3], [-4, -5, -6]];
+    let mut mag = init_sim(&samples);
+    assert_eq!(mag.sensor_interface.samples_measured(), 0);
+    assert_eq!(mag.get_mag_vector().unwrap(), [1, 2, 3]);
+    assert_eq!(mag.get_mag_vector().unwrap(), [-4, -5, -6]);
+}
+
+#[test]
+fn sim_partial_data_reads_do_not_consume_samples() {
+    let samples = [[1, 2, 3], [-4, -5, -6]];
+    let mut mag = init_sim(&samples);
+    assert!(mag.data_ready().unwrap());
+    mag.read_reg(Register::DataX).unwrap();
+    mag.read_reg(Register::DataY).unwrap();
+    assert!(mag.data_ready().unwrap());
+    assert_eq!(mag.get_mag_vector().unwrap(), [1, 2, 3]);
+    assert_eq!(mag.get_mag_vector().unwrap(), [-4, -5, -6]);
+}
+
+#[test]
+fn sim_ready_means_new_since_last_read() {
+    let clock = SimClock::new();
+    let samples = [[7, 8, 9]; 16];
+    let mut mag = HMC5983::new_with_interface(SimInterface::new_with_clock(
+        &samples, &clock,
+    ));
+    mag.init(&mut &clock).unwrap();
+    let period_us = mag.measurement_period_us() as u64;
+
+    clock.advance_us(period_us);
+    assert!(mag.data_ready().unwrap());
+    // still set: the sample hasn't been read
+    assert!(mag.data_ready().unwrap());
+    mag.get_mag_vector().unwrap();
+    assert!(!mag.data_ready().unwrap());
+    clock.advance_us(period_us);
+    assert!(mag.data_ready().unwrap());
+}
+
+#[test]
+fn sim_logs_writes() {
+    let mut mag = init_sim(&[]);
+    mag.sensor_interface.clear_writes();
+    mag.set_gain(GainSetting::Gain0230).unwrap();
+    assert_eq!(mag.release().writes(), &[(0x01, 0xE0)]);
+}
+
+const ALL_MODES: [MeasurementModeSetting; 4] = [
+    MeasurementModeSetting::NormalMode,
+    MeasurementModeSetting::PositiveBias,
+    MeasurementModeSetting::NegativeBias,
+    MeasurementModeSetting::TemperatureOnly,
+];
+
+const ALL_ODRS: [OdrSetting; 8] = [
+    OdrSetting::Odr0_75Hz,
+    OdrSetting::Odr1_5Hz,
+    OdrSetting::Odr3_0Hz,
+    OdrSetting::Odr7_5Hz,
+    OdrSetting::Odr15_0Hz,
+    OdrSetting::Odr30_0Hz,
+    OdrSetting::Odr75_0Hz,
+    OdrSetting::Odr220_0Hz,
+];
+
+const ALL_AVERAGING: [SampleAvgSetting; 4] = [
+    SampleAvgSetting::AvgSamples1,
+    SampleAvgSetting::AvgSamples2,
+    SampleAvgSetting::AvgSamples4,
+    SampleAvgSetting::AvgSamples8,
+];
+
+#[test]
+fn config_a_fields_at_datasheet_bits() {
+    // the four fields fill the byte, so every value must appear once
+    let mut seen = [false; 256];
+    for (ms, mode) in ALL_MODES.iter().enumerate() {
+        for (rate, odr) in ALL_ODRS.iter().enumerate() {
+            for (ma, avg) in ALL_AVERAGING.iter().enumerate() {
+                for temp in [false, true] {
+                    let val = encode_config_a(*mode, *odr, *avg, temp);
+                    assert_eq!(val >> 7, temp as u8);
+                    assert_eq!((val >> 5) & 0b11, ma as u8);
+                    assert_eq!((val >> 2) & 0b111, rate as u8);
+                    assert_eq!(val & 0b11, ms as u8);
+                    assert!(!seen[val as usize]);
+                    seen[val as usize] = true;
+                }
+            }
+        }
+    }
+    assert!(seen.iter().all(|s| *s));
+}
+
+#[test]
+fn data_block_read_rolls_back_to_data_x() {
+    let samples = [[0x0123, 0x0456, 0x0789]];
+    let mut mag = init_sim(&samples);
+    // from DataY, the pointer rolls back from 0x08 to 0x03: Y, X, Z
+    mag.read_block(Register::DataY, MAG_DATA_LEN).unwrap();
+    assert_eq!(
+        mag.block_buf[..MAG_DATA_LEN],
+        [0x04, 0x56, 0x01, 0x23, 0x07, 0x89]
+    );
+}
+
+#[test]
+fn config_data_status_read_gets_status_not_data() {
+    let samples = [[0x1234, 0x5678, 0x0ABC]];
+    let mut mag = init_sim(&samples);
+    let regs = mag.read_config_data_status_raw().unwrap();
+    assert_eq!(regs[..3], [0xF4, 0x40, 0x00]);
+    // X, Z, Y, each MSB first
+    assert_eq!(regs[3..9], [0x12, 0x34, 0x0A, 0xBC, 0x56, 0x78]);
+    // reading the data cleared RDY; a single 10-byte read would have
+    // rolled back and returned DataX MSB (0x12) here
+    assert_eq!(regs[9], 0x00);
+}
+
+#[test]
+fn bias_and_operating_mode_setters_write_their_registers() {
+    let mut mag = init_sim(&[]);
+    mag.sensor_interface.clear_writes();
+    mag.set_bias(MeasurementModeSetting::NegativeBias).unwrap();
+    mag.set_operating_mode(OperatingMode::Idle).unwrap();
+    mag.set_bias(MeasurementModeSetting::NormalMode).unwrap();
+    mag.set_operating_mode(OperatingMode::Single).unwrap();
+    // Config A keeps the init fields (0xF4) with MS1:MS0 in bits 1:0;
+    // Config C holds MD1:MD0
+    assert_eq!(
+        mag.sensor_interface.writes(),
+        &[(0x00, 0xF6), (0x02, 0x02), (0x00, 0xF4), (0x02, 0x01)]
+    );
+}
+
+#[test]
+fn self_test_waits_for_the_averaged_measurement() {
+    let clock = SimClock::new();
+    // the response to the positive bias field at the self-test gain
+    let samples = [[452, 452, 421]];
+    let mut mag = HMC5983::new_with_interface(SimInterface::new_with_clock(
+        &samples, &clock,
+    ));
+    let result = mag.self_test(&mut &clock).unwrap();
+    assert_eq!(result.reading, [452, 452, 421]);
+    assert!(result.all_passed());
+}
+
+#[test]
+fn temperature_from_known_raw_pair() {
+    let mut mag = init_sim(&[]);
+    // 0x0C80 = 3200 counts = 25 C above the 25 C offset
+    mag.sensor_interface.set_register(Register::TempMsb, 0x0C);
+    mag.sensor_interface.set_register(Register::TempLsb, 0x80);
+    assert_eq!(mag.get_temperature_raw_bytes().unwrap(), [0x0C, 0x80]);
+    assert_eq!(mag.get_temperature().unwrap(), 50);
+
+    // 0xF380 = -3200 counts, two's complement
+    mag.sensor_interface.set_register(Register::TempMsb, 0xF3);
+    assert_eq!(mag.get_temperature().unwrap(), 0);
+}
+
+#[test]
+fn autorange_returns_samples_at_the_reported_gain() {
+    let mut samples = [[100, 0, 0]; AUTORANGE_STEP_UP_SAMPLES as usize + 1];
+    samples[AUTORANGE_STEP_UP_SAMPLES as usize] = [133, 0, 0];
+    let mut mag = init_sim(&samples);
+    for _ in 1..AUTORANGE_STEP_UP_SAMPLES {
+        assert_eq!(mag.get_mag_vector_autorange(&mut NoDelay).unwrap()[0], 100);
+    }
+    // the last small reading steps finer; the result is measured there
+    let sample = mag.get_mag_vector_autorange(&mut NoDelay).unwrap();
+    assert_eq!(mag.config().gain, GainSetting::Gain1090);
+    assert_eq!(sample, [133, 0, 0]);
+}
+
+/// More samples than an `i32` sum of full-scale values can hold
+const OVERSAMPLE_PAST_I32: usize = 70_000;
+
+static FULL_SCALE_SAMPLES: [[i16; 3]; OVERSAMPLE_PAST_I32] =
+    [[i16::MAX, i16::MIN, -1]; OVERSAMPLE_PAST_I32];
+
+#[test]
+fn oversampled_sums_past_i32_range() {
+    let mut mag = init_sim(&FULL_SCALE_SAMPLES);
+    let avg = mag
+        .get_mag_vector_oversampled::<OVERSAMPLE_PAST_I32>(&mut NoDelay)
+        .unwrap();
+    assert_eq!(avg, [i16::MAX, i16::MIN, -1]);
+}
+
+#[cfg(feature = "fixed")]
+#[test]
+fn q16_agrees_with_float_scaling() {
+    // each sample is read twice: once in fixed point, once as float
+    let samples = [
+        [4095, -4095, 1],
+        [4095, -4095, 1],
+        [-2048, 777, -1],
+        [-2048, 777, -1],
+        [0, 123, -456],
+        [0, 123, -456],
+    ];
+    let mut mag = init_sim(&samples);
+    for _ in 0..samples.len() / 2 {
+        let fixed = mag.get_mag_vector_q16().unwrap();
+        let float = mag.get_mag_vector_scaled().unwrap();
+        for (q, f) in fixed.iter().zip(float.iter()) {
+            assert!((q.to_num::<f32>() - f).abs() < 1e-3, "{} vs {}", q, f);
+        }
+    }
+}
+
+#[test]
+fn init_writes_the_default_configuration() {
+    let mag = init_sim(&[]);
+    // 30 Hz, 8-sample averaging, temperature sensor on, normal bias;
+    // gain 820; continuous mode
+    assert_eq!(
+        mag.release().writes(),
+        &[(0x00, 0xF4), (0x01, 0x40), (0x02, 0x00)]
+    );
+}
+
+/// Read `n` samples at 220 Hz on an I2C bus clocked at `bus_hz`,
+/// returning how many the driver missed
+fn missed_at_high_rate(bus_hz: u32, n: usize) -> usize {
+    let clock = SimClock::new();
+    let mut samples = [[0i16; 3]; 256];
+    for (i, sample) in samples.iter_mut().enumerate() {
+        *sample = [i as i16, 0, 0];
+    }
+    let mut sim = SimInterface::new_with_clock(&samples, &clock);
+    sim.set_bus_hz(bus_hz);
+    let mut mag = HMC5983::new_with_interface(sim);
+    mag.init(&mut &clock).unwrap();
+    mag.configure_high_rate(&mut &clock).unwrap();
+    assert_eq!(mag.measurement_period_us(), 4_545);
+
+    let missed_before = mag.sensor_interface.missed_samples();
+    for _ in 0..n {
+        mag.read_mag_blocking_until_ready(None::<&NoDrdyPin>, 10, &mut &clock)
+            .unwrap();
+    }
+    mag.sensor_interface.missed_samples() - missed_before
+}
+
+#[test]
+fn high_rate_reads_fit_the_sample_period() {
+    let clock = SimClock::new();
+    let samples = [[1, 2, 3]; 8];
+    let mut sim = SimInterface::new_with_clock(&samples, &clock);
+    sim.set_bus_hz(100_000);
+    let mut mag = HMC5983::new_with_interface(sim);
+    mag.configure_high_rate(&mut &clock).unwrap();
+
+    // a sample is waiting: the read is one Status poll and one data read
+    clock.advance_us(mag.measurement_period_us() as u64);
+    let start = clock.now_us();
+    mag.read_mag_blocking_until_ready(None::<&NoDrdyPin>, 10, &mut &clock)
+        .unwrap();
+    let overhead_us = clock.now_us() - start;
+    assert!(overhead_us < mag.measurement_period_us() as u64 / 3);
+
+    // sustained, the driver keeps up at standard-mode I2C
+    assert_eq!(missed_at_high_rate(100_000, 100), 0);
+    // but not below the documented 27 kHz minimum
+    assert!(missed_at_high_rate(20_000, 100) > 0);
+}
+
+#[test]
+fn heading_relative_to_reference_axis() {
+    let mut mag = init_sim(&[]);
+    let references = [
+        (AxisSource::PlusX, [20.0, 0.0, -40.0]),
+        (AxisSource::PlusY, [0.0, 20.0, -40.0]),
+        (AxisSource::MinusX, [-20.0, 0.0, -40.0]),
+        (AxisSource::MinusY, [0.0, -20.0, -40.0]),
+    ];
+    // a field of [20, 20, -40] is 45° from +X toward +Y
+    let diagonal = [20.0, 20.0, -40.0];
+    for (i, (axis, north)) in references.iter().enumerate() {
+        mag.set_heading_reference(*axis).unwrap();
+        // the reference axis pointing north reads 0°
+        let heading = mag.heading_from_field(*north);
+        assert!(heading.abs() < 1e-3 || (heading - 360.0).abs() < 1e-3);
+        // each reference is 90° further round than the last
+        let expected = (45.0 + 270.0 * i as f32) % 360.0;
+        let heading = mag.heading_from_field(diagonal);
+        assert!((heading - expected).abs() < 1e-3, "{:?}: {}", axis, heading);
+    }
+    assert!(mag.set_heading_reference(AxisSource::PlusZ).is_err());
+    assert!(mag.set_heading_reference(AxisSource::MinusZ).is_err());
+}
+
+#[test]
+fn periodic_self_check_waits_out_multi_sample_reads() {
+    let samples = [[10, 20, 30]; 8];
+    let mut mag = init_sim(&samples);
+    mag.enable_periodic_self_check(1, 0.1);
+    mag.sensor_interface.clear_writes();
+
+    let mut burst = [[0; 3]; 4];
+    assert_eq!(mag.capture_burst(&mut burst, &mut NoDelay).unwrap(), 4);
+    assert_eq!(burst, [[10, 20, 30]; 4]);
+    let avg = mag.get_mag_vector_oversampled::<2>(&mut NoDelay).unwrap();
+    assert_eq!(avg, [10, 20, 30]);
+    // no bias reading was taken: the configuration was never touched
+    assert!(mag.sensor_interface.writes().is_empty());
+}
+
+#[test]
+fn burst_stores_saturated_samples_whatever_the_range_policy() {
+    let samples = [[10, 20, 30], [-4096, 20, 30], [11, 21, 31]];
+    let mut mag = init_sim(&samples);
+    mag.set_range_policy(RangePolicy::Error);
+
+    let mut burst = [[0; 3]; 3];
+    assert_eq!(mag.capture_burst(&mut burst, &mut NoDelay).unwrap(), 3);
+    assert_eq!(burst, samples);
+}
+
+#[test]
+fn periodic_self_check_discards_the_last_bias_reading() {
+    let clock = SimClock::new();
+    // every sample passes the self-test, and each is distinct
+    let mut samples = [[0i16; 3]; 32];
+    for (i, sample) in samples.iter_mut().enumerate() {
+        *sample = [300 + i as i16, 452, 421];
+    }
+    let mut mag = HMC5983::new_with_interface(SimInterface::new_with_clock(
+        &samples, &clock,
+    ));
+    mag.init(&mut &clock).unwrap();
+    mag.enable_periodic_self_check(1, 1.0);
+
+    let mut read = || {
+        mag.read_mag_blocking_until_ready(None::<&NoDrdyPin>, 100, &mut &clock)
+            .unwrap()
+    };
+    read();
+    // this read runs the check first
+    let after = read();
+    let bias = mag.read_health().unwrap().self_test.unwrap().reading;
+    assert!(after[0] > bias[0], "{:?} after {:?}", after, bias);
+    assert_eq!(mag.config().gain, GainSetting::Gain0820);
+}
+
+#[test]
+fn init_leaves_temperature_off_on_hmc5883() {
+    let mut mag = HMC5983::new_with_interface(SimInterface::new());
+    mag.set_variant(Variant::Hmc5883);
+    mag.init(&mut NoDelay).unwrap();
+    assert_eq!(mag.sensor_interface.register(Register::ConfigA), 0x74);
+    assert!(!mag.config().temp_enabled);
+}
+
+#[test]
+fn temperature_scale_follows_the_variant() {
+    let mut mag = init_sim(&[]);
+    assert_eq!(
+        Variant::Hmc5983.temperature_scale(),
+        Some(TemperatureScale {
+            lsb_per_degree: 128,
+            offset_celsius: 25,
+        })
+    );
+    assert_eq!(Variant::Hmc5883.temperature_scale(), None);
+
+    let custom = TemperatureScale {
+        lsb_per_degree: 64,
+        offset_celsius: 20,
+    };
+    mag.set_temperature_scale(custom).unwrap();
+    assert_eq!(mag.temperature_scale(), custom);
+    mag.set_variant(Variant::Hmc5983);
+    assert_eq!(mag.temperature_scale(), HMC5983_TEMPERATURE_SCALE);
+}
+
+#[test]
+fn temperature_scale_extremes() {
+    let mut mag = init_sim(&[]);
+    for lsb_per_degree in [0, -1, i16::MIN] {
+        let scale = TemperatureScale {
+            lsb_per_degree,
+            offset_celsius: 25,
+        };
+        assert!(mag.set_temperature_scale(scale).is_err());
+    }
+
+    // 32767 + 25 overflows i16
+    let scale = TemperatureScale {
+        lsb_per_degree: 1,
+        offset_celsius: 25,
+    };
+    mag.set_temperature_scale(scale).unwrap();
+    mag.sensor_interface.set_register(Register::TempMsb, 0x7F);
+    mag.sensor_interface.set_register(Register::TempLsb, 0xFF);
+    assert_eq!(mag.get_temperature().unwrap(), i16::MAX);
+}
+
+#[test]
+fn sample_rate_ignores_a_sample_already_waiting() {
+    let clock = SimClock::new();
+    let samples = [[1, 2, 3]; 64];
+    let mut mag = HMC5983::new_with_interface(SimInterface::new_with_clock(
+        &samples, &clock,
+    ));
+    mag.init(&mut &clock).unwrap();
+    // a sample arrives well before the measurement starts
+    clock.advance_us(mag.measurement_period_us() as u64 + 20_000);
+
+    let now_us = || clock.now_us() as u32;
+    let hz = mag.measure_sample_rate(500, now_us, &mut &clock).unwrap();
+    assert!((hz - 30.0).abs() < 0.3, "{}", hz);
+}
+
+/// Points around the square of half-width 400 counts, 25 counts apart:
+/// every direction in the horizontal plane, about 3.6° apart
+const SQUARE_POINTS: usize = 4 * 32;
+
+fn square_point(i: usize) -> [i16; 3] {
+    let t = (i % 32) as i16 * 25 - 400;
+    match i / 32 {
+        0 => [400, t, -300],
+        1 => [-t, 400, -300],
+        2 => [-400, -t, -300],
+        _ => [t, -400, -300],
+    }
+}
+
+#[test]
+fn integer_heading_agrees_with_float_heading() {
+    // each point is read twice: once for each heading
+    let mut samples = [[0; 3]; 2 * SQUARE_POINTS];
+    for (i, pair) in samples.chunks_mut(2).enumerate() {
+        pair.fill(square_point(i));
+    }
+    let mut mag = init_sim(&samples);
+    for _ in 0..SQUARE_POINTS {
+        let int = mag.get_heading_degrees_int().unwrap() as f32;
+        let float = mag.get_heading_degrees().unwrap();
+        let diff = (int - float).abs();
+        assert!(diff <= 1.0 || diff >= 359.0, "{} vs {}", int, float);
+    }
+}
+
+#[test]
+fn inclination_positive_for_a_downward_field() {
+    // level, with the field pointing down and north: +Z is up
+    let samples = [[300, 0, -300], [300, 0, 300]];
+    let mut mag = init_sim(&samples);
+    let dip = mag.get_inclination_degrees().unwrap();
+    assert!((dip - 45.0).abs() < 0.01, "{}", dip);
+    let dip = mag.get_inclination_degrees().unwrap();
+    assert!((dip + 45.0).abs() < 0.01, "{}", dip);
+}
+
+#[test]
+fn timeout_when_never_ready_leaves_the_next_read_clean() {
+    let clock = SimClock::new();
+    let mut samples = [[0i16; 3]; 16];
+    for (i, sample) in samples.iter_mut().enumerate() {
+        *sample = [i as i16, 0, 0];
+    }
+    let mut mag = HMC5983::new_with_interface(SimInterface::new_with_clock(
+        &samples, &clock,
+    ));
+    mag.init(&mut &clock).unwrap();
+    let [before, _, _] = mag
+        .read_mag_blocking_until_ready(None::<&NoDrdyPin>, 100, &mut &clock)
+        .unwrap();
+
+    // idle: no sample ever becomes ready
+    mag.set_operating_mode(OperatingMode::Idle).unwrap();
+    let start = clock.now_us();
+    let rc =
+        mag.read_mag_blocking_until_ready(None::<&NoDrdyPin>, 50, &mut &clock);
+    assert!(matches!(rc, Err(Error::Timeout)));
+    assert!(clock.now_us() - start >= 50_000);
+
+    // measuring again, the next read is the next sample, not a stale one
+    mag.set_operating_mode(OperatingMode::Continuous).unwrap();
+    let [after, _, _] = mag
+        .read_mag_blocking_until_ready(None::<&NoDrdyPin>, 100, &mut &clock)
+        .unwrap();
+    assert_eq!(after, before + 1);
+}
+
+#[cfg(feature = "verify-writes")]
+#[test]
+fn write_settle_waits_before_each_readback() {
+    let clock = SimClock::new();
+    let mut mag = init_sim(&[]);
+    mag.set_write_settle(5);
+    mag.set_gain_settled(GainSetting::Gain0230, &mut &clock)
+        .unwrap();
+    mag.set_operating_mode_settled(OperatingMode::Idle, &mut &clock)
+        .unwrap();
+    assert_eq!(clock.now_us(), 2 * 5);
+    // without a delay source there is no wait
+    mag.set_gain(GainSetting::Gain1090).unwrap();
+    assert_eq!(clock.now_us(), 2 * 5);
+}
+
+#[test]
+fn sample_window_keeps_the_latest_reads() {
+    let samples = [[1, 0, 0], [2, 0, 0], [3, 0, 0], [7, -3, 9]];
+    let mut mag = HMC5983::<_, 3>::new_with_window(
+        SimInterface::new_with_samples(&samples),
+    );
+    mag.init(&mut NoDelay).unwrap();
+    mag.get_mag_vector().unwrap();
+    mag.get_mag_vector().unwrap();
+    assert_eq!(mag.latest_n(), &[[1, 0, 0], [2, 0, 0]]);
+    // reads built on get_mag_vector are kept too
+    mag.get_mag_vector_scaled().unwrap();
+    mag.get_heading_degrees_int().unwrap();
+    assert_eq!(mag.latest_n(), &[[2, 0, 0], [3, 0, 0], [7, -3, 9]]);
+    assert_eq!(mag.moving_average(), [4.0, -1.0, 3.0]);
+
+    // off by default
+    let mut mag = init_sim(&samples);
+    mag.get_mag_vector().unwrap();
+    assert!(mag.latest_n().is_empty());
+}
+
+#[test]
+fn config_c_bytes_match_the_operating_mode_encoding() {
+    let mut mag = init_sim(&[]);
+    // init selects continuous mode: MD1:MD0 = 00, not a Config A code
+    assert_eq!(mag.sensor_interface.writes().last(), Some(&(0x02, 0x00)));
+    assert_eq!(mag.sensor_interface.register(Register::ConfigC), 0x00);
+
+    mag.sensor_interface.clear_writes();
+    for mode in [
+        OperatingMode::Idle,
+        OperatingMode::Single,
+        OperatingMode::Continuous,
+    ] {
+        mag.set_operating_mode(mode).unwrap();
+    }
+    assert_eq!(
+        mag.sensor_interface.writes(),
+        &[(0x02, 0x02), (0x02, 0x01), (0x02, 0x00)]
+    );
+}
+
+/// An I2C bus where every device reads `id`, or nothing acknowledges
+struct FakeI2c {
+    id: Option<u8>,
+}
+
+impl hal::blocking::i2c::Write for FakeI2c {
+    type Error = ();
+    fn write(&mut self, _addr: u8, _bytes: &[u8]) -> Result<(), ()> {
+        self.id.map(|_| ()).ok_or(())
+    }
+}
+
+impl hal::blocking::i2c::Read for FakeI2c {
+    type Error = ();
+    fn read(&mut self, _addr: u8, buf: &mut [u8]) -> Result<(), ()> {
+        buf.fill(self.id.ok_or(())?);
+        Ok(())
+    }
+}
+
+impl hal::blocking::i2c::WriteRead for FakeI2c {
+    type Error = ();
+    fn write_read(
+        &mut self,
+        _addr: u8,
+        _bytes: &[u8],
+        buf: &mut [u8],
+    ) -> Result<(), ()> {
+        buf.fill(self.id.ok_or(())?);
+        Ok(())
+    }
+}
+
+#[test]
+fn auto_detect_reports_the_failure_and_returns_the_bus() {
+    let empty = FakeI2c { id: None };
+    let err = HMC5983::auto_detect(empty, &mut NoDelay).err().unwrap();
+    assert!(matches!(err.failure(), DetectFailure::NoResponse(())));
+    assert_eq!(err.release().id, None);
+
+    let other = FakeI2c { id: Some(0x55) };
+    let (failure, bus) = HMC5983::auto_detect(other, &mut NoDelay)
+        .err()
+        .unwrap()
+        .into_parts();
+    assert!(matches!(
+        failure,
+        DetectFailure::UnknownDevice([0x55, 0x55, 0x55])
+    ));
+    assert_eq!(bus.id, Some(0x55));
+}
+
+#[test]
+fn ema_step_response() {
+    // 820 counts is 100 µT at the init gain
+    let samples = [[0, 0, 0], [820, 0, 0], [820, 0, 0], [820, 0, 0]];
+    let mut mag = init_sim(&samples);
+    mag.set_ema_alpha(0.5).unwrap();
+    for expected in [0.0, 50.0, 75.0, 87.5] {
+        let [x, y, z] = mag.get_mag_vector_calibrated().unwrap();
+        assert!((x - expected).abs() < 1e-3, "{} vs {}", x, expected);
+        assert_eq!([y, z], [0.0, 0.0]);
+    }
+    assert!(mag.set_ema_alpha(1.5).is_err());
+    assert!(mag.set_ema_alpha(f32::NAN).is_err());
+}
+
+#[test]
+fn calibration_residual_bypasses_the_ema() {
+    // rotating through a 100 µT field: every magnitude is the same
+    let samples = [[820, 0, 0], [0, 820, 0], [0, 0, 820], [-820, 0, 0]];
+    let mut mag = init_sim(&samples);
+    mag.set_ema_alpha(0.2).unwrap();
+    let residual = mag.calibration_residual(4, &mut NoDelay).unwrap();
+    assert!(residual < 1e-2, "{}", residual);
+}
+
+/// A sensor on a bus shared with other devices: each device holds a proxy
+/// borrowing the bus for the length of one transaction, as the proxies
+/// from the `shared-bus` crate do
+struct BusProxy<'a, 'b> {
+    bus: &'a core::cell::RefCell<SimInterface<'b>>,
+}
+
+impl hal::blocking::i2c::Write for BusProxy<'_, '_> {
+    type Error = ();
+    fn write(&mut self, _addr: u8, bytes: &[u8]) -> Result<(), ()> {
+        let mut chip = self.bus.borrow_mut();
+        match *bytes {
+            [reg, val] => chip.write_reg(reg, val).map_err(|_| ()),
+            // setting the register pointer for a read
+            [_] => Ok(()),
+            _ => Err(()),
+        }
+    }
+}
+
+impl hal::blocking::i2c::Read for BusProxy<'_, '_> {
+    type Error = ();
+    fn read(&mut self, _addr: u8, _buf: &mut [u8]) -> Result<(), ()> {
+        // only repeated-start reads are used here
+        Err(())
+    }
+}
+
+impl hal::blocking::i2c::WriteRead for BusProxy<'_, '_> {
+    type Error = ();
+    fn write_read(
+        &mut self,
+        _addr: u8,
+        bytes: &[u8],
+        buf: &mut [u8],
+    ) -> Result<(), ()> {
+        let mut chip = self.bus.borrow_mut();
+        chip.read_block(bytes[0], buf).map_err(|_| ())
+    }
+}
+
+impl hal::blocking::spi::Write<u8> for BusProxy<'_, '_> {
+    type Error = ();
+    fn write(&mut self, words: &[u8]) -> Result<(), ()> {
+        let mut chip = self.bus.borrow_mut();
+        chip.write_reg(words[0] & 0x3F, words[1]).map_err(|_| ())
+    }
+}
+
+impl hal::blocking::spi::Transfer<u8> for BusProxy<'_, '_> {
+    type Error = ();
+    fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], ()> {
+        let mut chip = self.bus.borrow_mut();
+        let (cmd, data) = words.split_first_mut().ok_or(())?;
+        chip.read_block(*cmd & 0x3F, data).map_err(|_| ())?;
+        Ok(words)
+    }
+}
+
+/// A chip select pin that needs no wiring
+struct NoCsPin;
+
+impl hal::digital::v2::OutputPin for NoCsPin {
+    type Error = ();
+    fn set_low(&mut self) -> Result<(), ()> {
+        Ok(())
+    }
+    fn set_high(&mut self) -> Result<(), ()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn drivers_on_shared_bus_proxies() {
+    let samples = [[1, 2, 3], [4, 5, 6]];
+    let bus =
+        core::cell::RefCell::new(SimInterface::new_with_samples(&samples));
+
+    let i2c = I2cInterface::new(BusProxy { bus: &bus });
+    let mut mag = HMC5983::new_with_interface(i2c);
+    mag.init(&mut NoDelay).unwrap();
+    // another device's proxy can use the bus in between
+    let mut other = BusProxy { bus: &bus };
+    hal::blocking::i2c::Write::write(&mut other, 0x1E, &[0x0A]).unwrap();
+    assert_eq!(mag.get_mag_vector().unwrap(), [1, 2, 3]);
+
+    let spi = SpiInterface::new(BusProxy { bus: &bus }, NoCsPin);
+    let mut mag = HMC5983::new_with_interface(spi);
+    mag.init(&mut NoDelay).unwrap();
+    assert_eq!(mag.get_mag_vector().unwrap(), [4, 5, 6]);
+}
+
+#[test]
+fn status_decodes_each_flag() {
+    let neither = Status::decode_status(0xFC);
+    assert!(!neither.data_ready && !neither.locked);
+    let ready = Status::decode_status(STATUS_RDY);
+    assert!(ready.data_ready && !ready.locked);
+    let locked = Status::decode_status(STATUS_LOCK);
+    assert!(!locked.data_ready && locked.locked);
+    let both = Status::decode_status(STATUS_RDY | STATUS_LOCK);
+    assert!(both.data_ready && both.locked);
+}
+
+#[test]
+fn data_encodings_decode_negative_values() {
+    // -100 in each encoding
+    assert_eq!(DataEncoding::TwosComplement.decode(0xFF, 0x9C), -100);
+    assert_eq!(DataEncoding::SignMagnitude.decode(0x80, 0x64), -100);
+    assert_eq!(DataEncoding::SignMagnitude.decode(0x00, 0x64), 100);
+}
+
+#[test]
+fn wrong_spi_mode_id_patterns() {
+    assert!(!looks_like_wrong_spi_mode(&EXPECTED_ID));
+    assert!(looks_like_wrong_spi_mode(&[0x00, 0x00, 0x00]));
+    assert!(looks_like_wrong_spi_mode(&[0xFF, 0xFF, 0xFF]));
+    // "H43" = 0x48 0x34 0x33, shifted left and right by one bit
+    assert!(looks_like_wrong_spi_mode(&[0x90, 0x68, 0x66]));
+    assert!(looks_like_wrong_spi_mode(&[0x90, 0x68, 0x67]));
+    assert!(looks_like_wrong_spi_mode(&[0x24, 0x1A, 0x19]));
+    assert!(looks_like_wrong_spi_mode(&[0xA4, 0x1A, 0x19]));
+    assert!(!looks_like_wrong_spi_mode(&[0x12, 0x34, 0x56]));
+}
+
+#[test]
+fn milligauss_matches_the_float_scaling_at_every_gain() {
+    const VALUES: [i16; 4] = [1, 7, 1000, 2047];
+    const GAINS: usize = 8;
+    // each value is read twice: once per conversion
+    let mut samples = [[0i16; 3]; GAINS * VALUES.len() * 2];
+    for (i, pair) in samples.chunks_mut(2).enumerate() {
+        let val = VALUES[i % VALUES.len()];
+        pair.fill([val, -val, val / 2 - val]);
+    }
+    let mut mag = init_sim(&samples);
+
+    let mut gain = Some(GainSetting::Gain1370);
+    while let Some(setting) = gain {
+        mag.set_gain(setting).unwrap();
+        // the first sample after a gain change repeats the previous one
+        mag.get_mag_vector().unwrap();
+        for _ in VALUES {
+            let milligauss = mag.get_mag_milligauss().unwrap();
+            let microtesla = mag.get_mag_vector_scaled().unwrap();
+            for (mg, ut) in milligauss.iter().zip(microtesla.iter()) {
+                // 1 uT = 10 mG
+                let diff = (*mg as f32 - ut * 10.0).abs();
+                assert!(diff <= 0.5 + 1e-2, "{:?}: {} vs {}", setting, mg, ut);
+            }
+        }
+        gain = setting.coarser();
+    }
+    assert_eq!(mag.sensor_interface.samples_measured(), samples.len());
+}
@@ -0,0 +1,109 @@
+/*
+Copyright (c) 2020 Todd Stellanova
+LICENSE: BSD3 (see LICENSE file)
+*/
+
+use crate::math;
+
+/// A sample axis
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Axis {
+    X,
+    Y,
+    Z,
+}
+
+/// Quantity compared against the threshold
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ThresholdSource {
+    /// Absolute value of one axis
+    Axis(Axis),
+    /// Magnitude of the field vector
+    Magnitude,
+}
+
+/// Threshold crossing reported for a sample
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Crossing {
+    /// No change in state
+    None,
+    /// Rose above the threshold
+    Rising,
+    /// Fell below the threshold minus the hysteresis
+    Falling,
+}
+
+/// Software threshold detector, for using the sensor as a magnet proximity
+/// or event detector (the chip has no threshold interrupt).
+/// Feed it each sample; it reports rising and falling edges, with
+/// hysteresis so a noisy field near the threshold does not chatter.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ThresholdWatcher {
+    source: ThresholdSource,
+    threshold: f32,
+    hysteresis: f32,
+    above: bool,
+}
+
+impl ThresholdWatcher {
+    /// `threshold` and `hysteresis` are in the units of the samples fed in
+    pub fn new(
+        source: ThresholdSource,
+        threshold: f32,
+        hysteresis: f32,
+    ) -> Self {
+        Self {
+            source,
+            threshold,
+            hysteresis: hysteresis.abs(),
+            above: false,
+        }
+    }
+
+    /// Whether the last sample left the watcher above the threshold
+    pub fn is_above(&self) -> bool {
+        self.above
+    }
+
+    /// Process one raw sample
+    pub fn update(&mut self, sample: [i16; 3]) -> Crossing {
+        self.update_f32([sample[0] as f32, sample[1] as f32, sample[2] as f32])
+    }
+
+    /// Process one scaled sample
+    pub fn update_f32(&mut self, sample: [f32; 3]) -> Crossing {
+        let value = match self.source {
+            ThresholdSource::Axis(axis) => sample[axis as usize].abs(),
+            ThresholdSource::Magnitude => math::sqrtf(
+                sample[0] * sample[0]
+                    + sample[1] * sample[1]
+                    + sample[2] * sample[2],
+            ),
+        };
+        if !self.above && value > self.threshold {
+            self.above = true;
+            Crossing::Rising
+        } else if self.above && value < self.threshold - self.hysteresis {
+            self.above = false;
+            Crossing::Falling
+        } else {
+            Crossing::None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn axis_source_watches_only_that_axis() {
+        let mut watcher =
+            ThresholdWatcher::new(ThresholdSource::Axis(Axis::Z), 100.0, 10.0);
+        assert_eq!(watcher.update([500, 500, 50]), Crossing::None);
+        assert_eq!(watcher.update([0, 0, -150]), Crossing::Rising);
+        // within the hysteresis band
+        assert_eq!(watcher.update([0, 0, 95]), Crossing::None);
+        assert_eq!(watcher.update([0, 0, 80]), Crossing::Falling);
+    }
+}
@@ -0,0 +1,76 @@
+/*
+Copyright (c) 2020 Todd Stellanova
+LICENSE: BSD3 (see LICENSE file)
+*/
+
+/// Holds the latest `N` samples, for moving-window filtering.
+/// Samples are kept in arrival order, so each push moves the window along
+/// by copying; that is cheap for the small windows this is meant for.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SampleWindow<const N: usize> {
+    samples: [[i16; 3]; N],
+    len: usize,
+}
+
+impl<const N: usize> SampleWindow<N> {
+    pub fn new() -> Self {
+        Self {
+            samples: [[0; 3]; N],
+            len: 0,
+        }
+    }
+
+    /// Add a sample, dropping the oldest once the window is full
+    pub fn push(&mut self, sample: [i16; 3]) {
+        if N == 0 {
+            return;
+        }
+        if self.len == N {
+            self.samples.copy_within(1.., 0);
+            self.samples[N - 1] = sample;
+        } else {
+            self.samples[self.len] = sample;
+            self.len += 1;
+        }
+    }
+
+    /// The samples in the window, oldest first (fewer than `N` until full)
+    pub fn latest_n(&self) -> &[[i16; 3]] {
+        &self.samples[..self.len]
+    }
+
+    /// Whether the window holds `N` samples
+    pub fn is_full(&self) -> bool {
+        self.len == N
+    }
+
+    /// Per-axis mean of the samples in the window, or zero if it is empty
+    pub fn moving_average(&self) -> [f32; 3] {
+        if self.len == 0 {
+            return [0.0; 3];
+        }
+        let mut sums = [0i64; 3];
+        for sample in self.latest_n() {
+            for (sum, val) in sums.iter_mut().zip(sample.iter()) {
+                *sum += *val as i64;
+            }
+        }
+        let count = self.len as f64;
+        [
+            (sums[0] as f64 / count) as f32,
+            (sums[1] as f64 / count) as f32,
+            (sums[2] as f64 / count) as f32,
+        ]
+    }
+
+    /// Discard all samples
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+}
+
+impl<const N: usize> Default for SampleWindow<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}